@@ -1,16 +1,23 @@
 use serde::Serialize;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, Mempool};
+use crate::blockchain::error::BlockchainError;
 use crate::miner::Handle as MinerHandle;
 use crate::txgen::Handle as TxHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
 use crate::types::hash::H256;
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::wallet::Wallet;
+use crate::wallet::hd::DerivationPath;
 
 use log::info;
+use ring::constant_time;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -20,27 +27,132 @@ pub struct Server {
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    wallet: Option<WalletApi>,
     tx_gen: TxHandle, 
 }
 
+/// The wallet behind the `/wallet/` endpoints, which sign with the node's own keys.
+///
+/// Every wallet request must carry `Authorization: Bearer <token>`. Browsers do not send that
+/// header cross-origin without a preflight, so a web page cannot make the node spend.
+#[derive(Clone)]
+pub struct WalletApi {
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub token: String,
+}
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+// inclusion proof of one account, verifiable against state_root from the block header alone
+#[derive(Serialize)]
+struct StateProofResponse {
+    block: String,
+    state_root: String,
+    address: String,
+    nonce: u64,
+    balance: Amount,
+    index: usize,
+    leaf_count: usize,
+    proof: Vec<String>,
+}
+
+// a connected peer
+#[derive(Serialize)]
+struct PeerResponse {
+    id: u64,
+    addr: String,
+    listen_addr: String,
+    direction: String,
+    protocol_version: u32,
+    best_height: u128, // at handshake
+    ban_score: u32,
+    queued: usize, // messages waiting to be written
+    dropped: u64, // messages dropped because the peer was too slow
+}
+
+// an address banned for misbehaving
+#[derive(Serialize)]
+struct BanResponse {
+    ip: String,
+    banned_until: u64, // unix seconds
+}
+
+// an account of the wallet at the tip
+#[derive(Serialize)]
+struct AccountResponse {
+    address: String,
+    path: Option<String>,
+    balance: Amount,
+    next_nonce: u64,
+}
+
+// a key added to the wallet
+#[derive(Serialize)]
+struct NewAddressResponse {
+    address: String,
+    path: String,
+}
+
+// a transfer signed by the wallet and submitted to the mempool
+#[derive(Serialize)]
+struct TransferResponse {
+    txid: String,
+    wtxid: String,
+    nonce: u64,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
+        respond_result!($req, $success, $message, 200)
+    }};
+    ( $req:expr, $success:expr, $message:expr, $status:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
         let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
+            .with_header(content_type)
+            .with_status_code($status);
         $req.respond(resp).unwrap();
     }};
 }
+// the wallet if the wallet endpoints are enabled and the request carries their token, otherwise
+// responds with an error and returns
+macro_rules! authorized_wallet {
+    ( $req:expr, $wallet_api:expr ) => {{
+        let wallet_api = match &$wallet_api {
+            Some(wallet_api) => wallet_api,
+            None => {
+                respond_result!($req, false, "wallet endpoints are disabled, set BITCOIN_API_TOKEN to enable them", 403);
+                return;
+            }
+        };
+        let expected = format!("Bearer {}", wallet_api.token);
+        let authorized = $req.headers().iter()
+            .filter(|header| header.field.equiv("Authorization"))
+            .any(|header| constant_time::verify_slices_are_equal(header.value.as_str().as_bytes(), expected.as_bytes()).is_ok());
+        if !authorized {
+            respond_result!($req, false, "missing or wrong bearer token", 401);
+            return;
+        }
+        Arc::clone(&wallet_api.wallet)
+    }};
+}
+// responds with an error and returns unless the request is a POST
+macro_rules! require_post {
+    ( $req:expr ) => {{
+        if *$req.method() != Method::Post {
+            respond_result!($req, false, "method not allowed, use POST", 405);
+            return;
+        }
+    }};
+}
 macro_rules! respond_json {
     ( $req:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -56,6 +168,8 @@ impl Server {
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        wallet: Option<WalletApi>,
         tx_gen: &TxHandle, 
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
@@ -64,6 +178,8 @@ impl Server {
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            wallet,
             tx_gen: tx_gen.clone(),
         };
         thread::spawn(move || {
@@ -71,6 +187,8 @@ impl Server {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let wallet_api = server.wallet.clone();
                 let tx_gen = server.tx_gen.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
@@ -78,7 +196,7 @@ impl Server {
                     let url = match base_url.join(req.url()) {
                         Ok(u) => u,
                         Err(e) => {
-                            respond_result!(req, false, format!("error parsing url: {}", e));
+                            respond_result!(req, false, format!("error parsing url: {}", e), 400);
                             return;
                         }
                     };
@@ -89,7 +207,7 @@ impl Server {
                             let lambda = match params.get("lambda") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing lambda");
+                                    respond_result!(req, false, "missing lambda", 400);
                                     return;
                                 }
                             };
@@ -99,7 +217,8 @@ impl Server {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("error parsing lambda: {}", e)
+                                        format!("error parsing lambda: {}", e),
+                                        400
                                     );
                                     return;
                                 }
@@ -113,7 +232,7 @@ impl Server {
                             let theta = match params.get("theta") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing theta");
+                                    respond_result!(req, false, "missing theta", 400);
                                     return;
                                 }
                             };
@@ -123,7 +242,8 @@ impl Server {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("error parsing theta: {}", e)
+                                        format!("error parsing theta: {}", e),
+                                        400
                                     );
                                     return;
                                 }
@@ -135,6 +255,53 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            let peers: Vec<PeerResponse> = network.peers()
+                                .into_iter()
+                                .map(|peer| PeerResponse {
+                                    id: peer.id.0,
+                                    addr: peer.addr.to_string(),
+                                    listen_addr: peer.info.listen_addr.to_string(),
+                                    direction: format!("{:?}", peer.info.direction).to_lowercase(),
+                                    protocol_version: peer.info.protocol_version,
+                                    best_height: peer.info.best_height,
+                                    ban_score: peer.ban_score,
+                                    queued: peer.queued,
+                                    dropped: peer.dropped,
+                                })
+                                .collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/bans" => {
+                            let bans: Vec<BanResponse> = network.bans()
+                                .into_iter()
+                                .map(|(ip, banned_until)| BanResponse { ip: ip.to_string(), banned_until })
+                                .collect();
+                            respond_json!(req, bans);
+                        }
+                        "/network/bans/clear" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // without an ip, every ban is lifted
+                            let ip = match params.get("ip").map(|ip| ip.parse::<std::net::IpAddr>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing ip: {}", e),
+                                        400
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            if network.unban(ip) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "not banned", 404);
+                            }
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -147,7 +314,13 @@ impl Server {
                             let mut transactions: Vec<Vec<String>> = Vec::new();
 
                             for el in v {
-                                let block = blockchain.get_parent_block(el);
+                                let block = match blockchain.get_parent_block(el) {
+                                    Ok(block) => block,
+                                    Err(e) => {
+                                        respond_result!(req, false, e, 404);
+                                        return;
+                                    }
+                                };
                                 let signed_tx_vec = block.get_hashed_content();
                                 let signed_tx_vec_string: Vec<String> = signed_tx_vec.into_iter().map(|h|h.to_string()).collect();
                                 transactions.push(signed_tx_vec_string);
@@ -166,27 +339,77 @@ impl Server {
                             let block = match params.get("block") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing block");
+                                    respond_result!(req, false, "missing block", 400);
                                     return;
                                 }
                             };
-                            let block = match block.parse::<u64>() {
+                            let block = match block.parse::<u128>() {
                                 Ok(v) => v,
                                 Err(e) => {
                                     respond_result!(
                                         req,
                                         false,
-                                        format!("error parsing block: {}", e)
+                                        format!("error parsing block: {}", e),
+                                        400
                                     );
                                     return;
                                 }
                             };
 
                             let blockchain = blockchain.lock().unwrap();
-                            let v = blockchain.all_blocks_in_longest_chain();
-                            let block_hash = v[(block as usize)];
-                            let block = blockchain.get_parent_block(block_hash);
-                            let state = block.get_state().get_state();
+                            let (block_hash, state) = match blockchain.hash_at_height(block)
+                                .and_then(|hash| Ok((hash, blockchain.get_state(hash)?)))
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e, 404);
+                                    return;
+                                }
+                            };
+
+                            // with an address, return the account with its inclusion proof
+                            if let Some(address) = params.get("address") {
+                                let address = match address.parse::<Address>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing address: {}", e),
+                                            400
+                                        );
+                                        return;
+                                    }
+                                };
+                                let proof = match state.proof(address) {
+                                    Some(proof) => proof,
+                                    None => {
+                                        respond_result!(req, false, BlockchainError::UnknownAccount(address), 404);
+                                        return;
+                                    }
+                                };
+                                let header_root = match blockchain.get_parent_block(block_hash) {
+                                    Ok(block) => block.get_state_root(),
+                                    Err(e) => {
+                                        respond_result!(req, false, e, 404);
+                                        return;
+                                    }
+                                };
+                                let output = StateProofResponse {
+                                    block: block_hash.to_string(),
+                                    state_root: header_root.to_string(),
+                                    address: proof.account.address.to_string(),
+                                    nonce: proof.account.nonce,
+                                    balance: proof.account.balance,
+                                    index: proof.index,
+                                    leaf_count: proof.leaf_count,
+                                    proof: proof.siblings.iter().map(|h| h.to_string()).collect(),
+                                };
+                                respond_json!(req, output);
+                                return;
+                            }
+
+                            let state = state.get_state();
 
                             let mut output: Vec<String> = Vec::new();
 
@@ -205,6 +428,149 @@ impl Server {
                             }
                             respond_json!(req, output);
                         }
+                        "/wallet/accounts" => {
+                            let wallet = authorized_wallet!(req, wallet_api);
+                            let state = {
+                                let blockchain = blockchain.lock().unwrap();
+                                match blockchain.get_state(blockchain.tip()) {
+                                    Ok(state) => state,
+                                    Err(e) => {
+                                        respond_result!(req, false, e, 500);
+                                        return;
+                                    }
+                                }
+                            };
+                            let accounts: Vec<AccountResponse> = wallet.lock().unwrap().accounts(&state)
+                                .into_iter()
+                                .map(|account| AccountResponse {
+                                    address: account.address.to_string(),
+                                    path: account.path,
+                                    balance: account.balance,
+                                    next_nonce: account.next_nonce,
+                                })
+                                .collect();
+                            respond_json!(req, accounts);
+                        }
+                        "/wallet/new-address" => {
+                            require_post!(req);
+                            let wallet = authorized_wallet!(req, wallet_api);
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // without a path, derive the next account
+                            let path = match params.get("path").map(|path| path.parse::<DerivationPath>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing path: {}", e),
+                                        400
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let mut wallet = wallet.lock().unwrap();
+                            let added = match &path {
+                                Some(path) => wallet.derive_address(path),
+                                None => wallet.new_address(),
+                            };
+                            let address = match added {
+                                Ok(address) => address,
+                                Err(e) => {
+                                    respond_result!(req, false, e, 500);
+                                    return;
+                                }
+                            };
+                            let path = wallet.path_of(&address).map(|path| path.to_string()).unwrap_or_default();
+                            respond_json!(req, NewAddressResponse { address: address.to_string(), path });
+                        }
+                        "/wallet/transfer" => {
+                            require_post!(req);
+                            let wallet = authorized_wallet!(req, wallet_api);
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut addresses = Vec::new();
+                            for name in ["from", "to"] {
+                                let address = match params.get(name) {
+                                    Some(v) => v,
+                                    None => {
+                                        respond_result!(req, false, format!("missing {}", name), 400);
+                                        return;
+                                    }
+                                };
+                                match address.parse::<Address>() {
+                                    Ok(v) => addresses.push(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing {}: {}", name, e),
+                                            400
+                                        );
+                                        return;
+                                    }
+                                }
+                            }
+                            let mut amounts = Vec::new();
+                            for name in ["value", "fee"] {
+                                // the fee may be left out
+                                let amount = match (params.get(name), name) {
+                                    (Some(v), _) => v.as_str(),
+                                    (None, "fee") => "0",
+                                    (None, _) => {
+                                        respond_result!(req, false, format!("missing {}", name), 400);
+                                        return;
+                                    }
+                                };
+                                match amount.parse::<Amount>() {
+                                    Ok(v) => amounts.push(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing {}: {}", name, e),
+                                            400
+                                        );
+                                        return;
+                                    }
+                                }
+                            }
+
+                            let (state, chain_id) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                match blockchain.get_state(blockchain.tip()) {
+                                    Ok(state) => (state, blockchain.chain_id()),
+                                    Err(e) => {
+                                        respond_result!(req, false, e, 500);
+                                        return;
+                                    }
+                                }
+                            };
+                            // after the sender's pending transfers, so that it takes the next nonce
+                            let state = mempool.lock().unwrap().pending_state(&state, addresses[0]);
+                            let transfer = wallet.lock().unwrap()
+                                .transfer(&state, &chain_id, addresses[0], addresses[1], amounts[0], amounts[1]);
+                            let tx = match transfer {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, e, 400);
+                                    return;
+                                }
+                            };
+                            if !mempool.lock().unwrap().insert(tx.txid(), &tx) {
+                                respond_result!(req, false, "mempool refused the transaction: a transaction with the same nonce is pending, or the fee is too low", 409);
+                                return;
+                            }
+                            // signed here, so its witness is known to verify
+                            blockchain.lock().unwrap().signature_cache().insert(tx.wtxid());
+                            network.broadcast(Message::NewTransactionHashes(vec![tx.txid()]));
+                            respond_json!(req, TransferResponse {
+                                txid: tx.txid().to_string(),
+                                wtxid: tx.wtxid().to_string(),
+                                nonce: tx.get_t().get_nonce(),
+                            });
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
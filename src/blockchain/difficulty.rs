@@ -0,0 +1,197 @@
+use crate::blockchain::Blockchain;
+use crate::blockchain::error::BlockchainError;
+use crate::types::hash::H256;
+use crate::types::uint::U256;
+use std::convert::{TryFrom, TryInto};
+
+/// Largest factor by which one retarget can make mining harder or easier
+pub const MAX_ADJUSTMENT: u64 = 4;
+
+/// Consensus parameters of difficulty retargeting; every node of a network must use the same ones.
+#[derive(Debug, Clone, Copy)]
+pub struct RetargetParams {
+    /// Number of blocks between two retargets
+    pub interval: u128,
+    /// Average time between blocks that retargeting converges on, in milliseconds
+    pub target_block_time: u128,
+}
+
+impl Default for RetargetParams {
+    fn default() -> Self {
+        RetargetParams {interval: 16, target_block_time: 10_000}
+    }
+}
+
+impl RetargetParams {
+    /// Checks that retargeting with these parameters can neither overflow nor clamp the window
+    /// timespan to zero.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 {
+            return Err("retarget interval must be at least 1".to_string());
+        }
+        if self.target_block_time < MAX_ADJUSTMENT as u128 {
+            return Err(format!("block time must be at least {} milliseconds", MAX_ADJUSTMENT));
+        }
+        if self.expected_timespan().is_none() {
+            return Err("retarget interval times block time must fit in 64 bits".to_string());
+        }
+        Ok(())
+    }
+
+    // milliseconds one retarget window is expected to take
+    fn expected_timespan(&self) -> Option<u64> {
+        self.interval.checked_mul(self.target_block_time)?.try_into().ok()
+    }
+}
+
+/// Expected number of hashes needed to find a block with this target, `2^256 / (target + 1)`.
+///
+/// Fork choice sums this over a chain, so a chain of fewer but harder blocks can outweigh a longer
+/// one of easy blocks.
+pub fn work(target: &H256) -> U256 {
+    let target = U256::from(target);
+    if target == U256::MAX {
+        return U256::from(1);
+    }
+    // 2^256 does not fit, but 2^256 / (t + 1) = (2^256 - t - 1) / (t + 1) + 1 and 2^256 - t - 1 = !t
+    let divisor = target.checked_add(&U256::from(1)).unwrap();
+    (!target).div(&divisor).saturating_add(&U256::from(1))
+}
+
+/// Whether the block at this height carries a new target rather than its parent's
+pub fn is_retarget_height(params: &RetargetParams, height: u128) -> bool {
+    params.validate().is_ok() && height.is_multiple_of(params.interval) && height > params.interval
+}
+
+/// Difficulty target a block on top of `parent` must carry.
+///
+/// Every `interval` blocks the target is scaled by how long the last `interval` blocks actually
+/// took compared to `interval * target_block_time`, limited to a factor of `MAX_ADJUSTMENT` either
+/// way and never easier than the genesis target. Windows reaching back to genesis are skipped since
+/// the genesis timestamp is fixed. In between retargets the parent's target carries forward.
+pub fn next_difficulty(blockchain: &Blockchain, parent: H256) -> Result<H256, BlockchainError> {
+    let params = blockchain.retarget_params();
+    let parent_block = blockchain.get_parent_block(parent)?;
+    let height = blockchain.height(parent)? + 1;
+    if !is_retarget_height(&params, height) {
+        return Ok(parent_block.get_difficulty());
+    }
+
+    // first block of the window, so that the window spans `interval` block intervals
+    let mut first = parent;
+    for _ in 0..params.interval {
+        first = blockchain.get_parent_block(first)?.get_parent();
+    }
+    let first_block = blockchain.get_parent_block(first)?;
+
+    // validated by `is_retarget_height`
+    let expected = params.expected_timespan().unwrap();
+    let actual = parent_block.get_timestamp().saturating_sub(first_block.get_timestamp());
+    let actual = u64::try_from(actual).unwrap_or(u64::MAX);
+    let actual = actual.clamp(expected / MAX_ADJUSTMENT, expected.saturating_mul(MAX_ADJUSTMENT));
+
+    let max_target = U256::from(blockchain.max_difficulty());
+    let target = U256::from(parent_block.get_difficulty())
+        .checked_mul_u64(actual)
+        .map(|product| product.div_u64(expected))
+        .unwrap_or(max_target);
+    // a zero target could never be met
+    let target = std::cmp::max(target, U256::from(1));
+    Ok(std::cmp::min(target, max_target).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block;
+    use crate::types::hash::Hashable;
+    use crate::types::merkle::MerkleTree;
+    use ring::signature::Ed25519KeyPair;
+
+    fn blockchain(params: RetargetParams) -> Blockchain {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::new(key_pair);
+        blockchain.set_retarget_params(params);
+        blockchain
+    }
+
+    // extend the tip with empty blocks spaced `spacing` milliseconds apart, using the expected target
+    fn extend(blockchain: &mut Blockchain, count: usize, spacing: u128) {
+        for _ in 0..count {
+            let parent = blockchain.tip();
+            let parent_block = blockchain.get_parent_block(parent).unwrap();
+            let difficulty = next_difficulty(blockchain, parent).unwrap();
+            let merkle_root = MerkleTree::new(&Vec::<H256>::new()).root();
+            let state_root = blockchain.get_state(parent).unwrap().root();
+            let timestamp = std::cmp::max(parent_block.get_timestamp(), 1_000_000) + spacing;
+            let header = block::build_header(parent, rand::random(), difficulty, timestamp, merkle_root, state_root);
+            let new_block = block::build_block(header, block::build_content(vec![]));
+            blockchain.insert(&new_block);
+            assert_eq!(blockchain.tip(), new_block.hash());
+        }
+    }
+
+    fn difficulty_at_tip(blockchain: &Blockchain) -> U256 {
+        blockchain.get_parent_block(blockchain.tip()).unwrap().get_difficulty().into()
+    }
+
+    #[test]
+    fn fast_blocks_make_mining_harder() {
+        let params = RetargetParams {interval: 4, target_block_time: 1000};
+        let mut blockchain = blockchain(params);
+        let initial = difficulty_at_tip(&blockchain);
+
+        // first window touches genesis and is skipped
+        extend(&mut blockchain, 7, 500);
+        assert_eq!(difficulty_at_tip(&blockchain), initial);
+
+        // blocks twice as fast as the goal halve the target
+        extend(&mut blockchain, 1, 500);
+        assert_eq!(difficulty_at_tip(&blockchain), initial.div_u64(2));
+
+        // no change until the next retarget
+        extend(&mut blockchain, 3, 10);
+        assert_eq!(difficulty_at_tip(&blockchain), initial.div_u64(2));
+
+        // way too fast blocks are limited to a factor of four
+        extend(&mut blockchain, 1, 10);
+        let limited = initial.div_u64(2).div_u64(MAX_ADJUSTMENT);
+        assert_eq!(difficulty_at_tip(&blockchain), limited);
+    }
+
+    #[test]
+    fn degenerate_params_are_rejected() {
+        assert!(RetargetParams::default().validate().is_ok());
+        assert!(RetargetParams {interval: 0, target_block_time: 1000}.validate().is_err());
+        assert!(RetargetParams {interval: 4, target_block_time: 3}.validate().is_err());
+        assert!(RetargetParams {interval: 1, target_block_time: MAX_ADJUSTMENT as u128}.validate().is_ok());
+        assert!(RetargetParams {interval: u128::MAX, target_block_time: 1000}.validate().is_err());
+        assert!(RetargetParams {interval: 1 << 40, target_block_time: 1 << 40}.validate().is_err());
+
+        // invalid params never retarget rather than divide by a zero timespan
+        let params = RetargetParams {interval: 1, target_block_time: 1};
+        let mut blockchain = blockchain(params);
+        let initial = difficulty_at_tip(&blockchain);
+        extend(&mut blockchain, 4, 0);
+        assert_eq!(difficulty_at_tip(&blockchain), initial);
+    }
+
+    #[test]
+    fn work_of_targets() {
+        assert_eq!(work(&[0xff; 32].into()), U256::from(1));
+        let half: H256 = hex_literal::hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(work(&half), U256::from(2));
+        let harder: H256 = hex_literal::hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(work(&harder), U256::from(1 << 16));
+        assert_eq!(work(&H256::from([0; 32])), U256::MAX);
+    }
+
+    #[test]
+    fn slow_blocks_never_exceed_genesis_target() {
+        let params = RetargetParams {interval: 4, target_block_time: 1000};
+        let mut blockchain = blockchain(params);
+        let initial = difficulty_at_tip(&blockchain);
+        extend(&mut blockchain, 16, 3000);
+        assert_eq!(difficulty_at_tip(&blockchain), initial);
+    }
+}
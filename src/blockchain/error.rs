@@ -0,0 +1,31 @@
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use std::fmt;
+
+/// Failed lookups in the blockchain and mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockchainError {
+    /// No block with this hash is in the blockchain
+    UnknownBlock(H256),
+    /// No transaction with this hash is in the mempool
+    UnknownTransaction(H256),
+    /// The account does not exist in the state
+    UnknownAccount(Address),
+    /// The longest chain is not this high
+    HeightOutOfRange { height: u128, tip_height: u128 },
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockchainError::UnknownBlock(hash) => write!(f, "unknown block {}", hash),
+            BlockchainError::UnknownTransaction(hash) => write!(f, "unknown transaction {}", hash),
+            BlockchainError::UnknownAccount(address) => write!(f, "unknown account {}", address),
+            BlockchainError::HeightOutOfRange { height, tip_height } => {
+                write!(f, "height {} above tip height {}", height, tip_height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
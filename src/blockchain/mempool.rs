@@ -0,0 +1,349 @@
+use crate::blockchain::error::BlockchainError;
+use crate::blockchain::validation::{self, TransactionError};
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::block::State;
+use crate::types::hash::H256;
+use crate::types::transaction::SignedTransaction;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+/// Most transactions kept in the mempool
+pub const MAX_MEMPOOL_TXS: usize = 10_000;
+/// Most serialized transaction bytes kept in the mempool
+pub const MAX_MEMPOOL_BYTES: usize = 4 * 1024 * 1024;
+
+// structure to store received valid transactions not included blockchain yet
+//
+// Transactions are grouped per sender and ordered by nonce, so that blocks take each sender's
+// transactions in sequence. Across senders the fee decides: blocks take the highest paying ready
+// transactions, and a full mempool makes room by dropping the lowest paying ones.
+pub struct Mempool {
+    mem_pool: HashMap<H256, SignedTransaction>, // key = txid
+    by_sender: HashMap<Address, BTreeMap<u64, H256>>, // key = sender, value = nonce -> hash
+    bytes: usize, // serialized size of all transactions
+    max_txs: usize,
+    max_bytes: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mempool {
+    // constructor
+    pub fn new() -> Self {
+        Self::with_limits(MAX_MEMPOOL_TXS, MAX_MEMPOOL_BYTES)
+    }
+
+    /// Create a mempool with custom size limits
+    pub fn with_limits(max_txs: usize, max_bytes: usize) -> Self {
+        Self {
+            mem_pool: HashMap::new(),
+            by_sender: HashMap::new(),
+            bytes: 0,
+            max_txs,
+            max_bytes,
+        }
+    }
+
+    // checks if present
+    pub fn is_present(&self, hash: H256) -> bool {
+        self.mem_pool.contains_key(&hash)
+    }
+
+    // get transaction
+    pub fn get_transaction(&self, hash: H256) -> Result<SignedTransaction, BlockchainError> {
+        self.mem_pool.get(&hash).cloned().ok_or(BlockchainError::UnknownTransaction(hash))
+    }
+
+    // get mem_pool
+    pub fn get_mempool(&self) -> HashMap<H256, SignedTransaction> {
+        self.mem_pool.clone()
+    }
+
+    /// Number of transactions in the mempool
+    pub fn len(&self) -> usize {
+        self.mem_pool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mem_pool.is_empty()
+    }
+
+    /// Serialized size of all transactions in the mempool
+    pub fn size_in_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Insert a transaction whose signature was checked, evicting the lowest paying ones if the
+    /// mempool is over its limits.
+    /// Returns false if the transaction is not kept: it is already present, another transaction
+    /// of the sender has the same nonce, or it pays too little to make room for itself.
+    pub fn insert(&mut self, hash: H256, trans: &SignedTransaction) -> bool {
+        if self.mem_pool.contains_key(&hash) {
+            return false;
+        }
+        let sender = sender_of(trans);
+        let nonce = trans.get_t().get_nonce();
+        if self.by_sender.get(&sender).is_some_and(|nonces| nonces.contains_key(&nonce)) {
+            return false;
+        }
+
+        self.by_sender.entry(sender).or_default().insert(nonce, hash);
+        self.bytes += size_of(trans);
+        self.mem_pool.insert(hash, trans.clone());
+
+        while self.mem_pool.len() > self.max_txs || self.bytes > self.max_bytes {
+            match self.eviction_candidate() {
+                Some(lowest) => self.remove(lowest),
+                None => break,
+            }
+        }
+        self.mem_pool.contains_key(&hash)
+    }
+
+    // the lowest paying transaction among the last one of every sender, so that evicting it never
+    // leaves a gap in a sender's nonces
+    fn eviction_candidate(&self) -> Option<H256> {
+        self.by_sender.values()
+            .filter_map(|nonces| nonces.values().next_back())
+            .min_by_key(|hash| (self.mem_pool[hash].get_t().get_fee(), Reverse(**hash)))
+            .copied()
+    }
+
+    // remove transaction from hashmap
+    pub fn remove(&mut self, hash: H256) {
+        let tx = match self.mem_pool.remove(&hash) {
+            Some(tx) => tx,
+            None => return,
+        };
+        self.bytes -= size_of(&tx);
+
+        let sender = sender_of(&tx);
+        if let Some(nonces) = self.by_sender.get_mut(&sender) {
+            nonces.remove(&tx.get_t().get_nonce());
+            if nonces.is_empty() {
+                self.by_sender.remove(&sender);
+            }
+        }
+    }
+
+    /// Remove transactions that can never become valid on top of this state
+    pub fn prune(&mut self, state: &State) {
+        let mut to_remove: Vec<H256> = Vec::new();
+        for (k, v) in self.mem_pool.iter() {
+            match validation::check_transaction(state, v) {
+                Ok(()) => {}
+                // a transaction ahead of the account nonce may still become valid
+                Err(TransactionError::InvalidNonce { expected, found }) if found > expected => {}
+                Err(_) => to_remove.push(*k),
+            }
+        }
+        for el in to_remove {
+            self.remove(el);
+        }
+    }
+
+    /// Pick up to `max_txs` transactions for a block on top of `state`, highest fee first.
+    ///
+    /// A sender's transactions are only taken in nonce order starting right after the account
+    /// nonce, and each one is applied before the next is considered, so the result always applies
+    /// cleanly to `state` in the returned order.
+    pub fn select_for_block(&self, state: &State, max_txs: usize) -> Vec<SignedTransaction> {
+        let mut state = state.clone();
+        let mut selected: Vec<SignedTransaction> = Vec::new();
+
+        // the next transaction of every sender, keyed by fee, ties broken by hash
+        let mut ready: BinaryHeap<(Amount, Reverse<H256>, Address)> = BinaryHeap::new();
+        for (sender, nonces) in self.by_sender.iter() {
+            if let Some(hash) = self.next_of(&state, sender, nonces) {
+                ready.push((self.mem_pool[&hash].get_t().get_fee(), Reverse(hash), *sender));
+            }
+        }
+
+        while selected.len() < max_txs {
+            let (_, Reverse(hash), sender) = match ready.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let tx = &self.mem_pool[&hash];
+            if validation::apply_transaction(&mut state, tx).is_err() {
+                // later transactions of this sender cannot apply either
+                continue;
+            }
+            selected.push(tx.clone());
+
+            if let Some(next) = self.next_of(&state, &sender, &self.by_sender[&sender]) {
+                ready.push((self.mem_pool[&next].get_t().get_fee(), Reverse(next), sender));
+            }
+        }
+        selected
+    }
+
+    /// The state after applying the pending transactions of `sender` that follow on from `state`
+    /// in nonce order, so that a new transaction from it takes the next free nonce and can only
+    /// spend what the pending ones leave
+    pub fn pending_state(&self, state: &State, sender: Address) -> State {
+        let mut state = state.clone();
+        if let Some(nonces) = self.by_sender.get(&sender) {
+            while let Some(hash) = self.next_of(&state, &sender, nonces) {
+                if validation::apply_transaction(&mut state, &self.mem_pool[&hash]).is_err() {
+                    break;
+                }
+            }
+        }
+        state
+    }
+
+    // the transaction of a sender whose nonce follows the account nonce in the state
+    fn next_of(&self, state: &State, sender: &Address, nonces: &BTreeMap<u64, H256>) -> Option<H256> {
+        let (account_nonce, _) = state.get(*sender)?;
+        nonces.get(&account_nonce.checked_add(1)?).copied()
+    }
+}
+
+fn sender_of(tx: &SignedTransaction) -> Address {
+    tx.get_t().get_sender()
+}
+
+fn size_of(tx: &SignedTransaction) -> usize {
+    bincode::serialized_size(tx).unwrap_or(0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::{self, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed(key: &Ed25519KeyPair, value: u64, fee: u64, nonce: u64) -> SignedTransaction {
+        let t = Transaction::new(address(key), [9; 20].into(), Amount::new(value), Amount::new(fee), nonce);
+        let sig = transaction::sign(&t, &H256::default(), key).as_ref().to_vec();
+        SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+    }
+
+    fn address(key: &Ed25519KeyPair) -> Address {
+        Address::from_public_key_bytes(key.public_key().as_ref())
+    }
+
+    fn hashes(txs: &[SignedTransaction]) -> Vec<H256> {
+        txs.iter().map(|tx| tx.hash()).collect()
+    }
+
+    #[test]
+    fn selects_by_fee_in_nonce_order() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let mut state = State::new();
+        state.insert(address(&alice), 0, Amount::new(1000));
+        state.insert(address(&bob), 4, Amount::new(1000));
+
+        // alice's second transaction pays the most, but has to wait for her first one
+        let a1 = signed(&alice, 10, 1, 1);
+        let a2 = signed(&alice, 10, 50, 2);
+        let a4 = signed(&alice, 10, 90, 4); // gap at nonce 3
+        let b5 = signed(&bob, 10, 20, 5);
+        let b6 = signed(&bob, 10, 2, 6);
+
+        let mut mempool = Mempool::new();
+        for tx in [&a2, &b6, &a4, &b5, &a1] {
+            assert!(mempool.insert(tx.hash(), tx));
+        }
+        assert!(!mempool.insert(a1.hash(), &a1));
+        // a different transaction with a nonce already taken
+        assert!(!mempool.insert(signed(&alice, 11, 1, 1).hash(), &signed(&alice, 11, 1, 1)));
+
+        let selected = mempool.select_for_block(&state, 10);
+        assert_eq!(hashes(&selected), hashes(&[b5.clone(), b6.clone(), a1.clone(), a2.clone()]));
+        assert!(validation::apply_transactions(&state, &selected).is_ok());
+
+        assert_eq!(hashes(&mempool.select_for_block(&state, 3)), hashes(&[b5, b6, a1]));
+    }
+
+    #[test]
+    fn skips_senders_that_cannot_pay() {
+        let alice = key_pair::random();
+        let mut state = State::new();
+        state.insert(address(&alice), 0, Amount::new(25));
+
+        let mut mempool = Mempool::new();
+        let a1 = signed(&alice, 10, 5, 1);
+        let a2 = signed(&alice, 10, 5, 2);
+        mempool.insert(a1.hash(), &a1);
+        mempool.insert(a2.hash(), &a2);
+
+        assert_eq!(hashes(&mempool.select_for_block(&state, 10)), hashes(&[a1]));
+    }
+
+    #[test]
+    fn evicts_lowest_fee_without_gaps() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let mut mempool = Mempool::with_limits(3, MAX_MEMPOOL_BYTES);
+
+        let a1 = signed(&alice, 10, 1, 1);
+        let a2 = signed(&alice, 10, 30, 2);
+        let b1 = signed(&bob, 10, 20, 1);
+        mempool.insert(a1.hash(), &a1);
+        mempool.insert(a2.hash(), &a2);
+        mempool.insert(b1.hash(), &b1);
+
+        // a1 pays least, but evicting it would strand a2, so the cheapest last transaction goes
+        let b2 = signed(&bob, 10, 40, 2);
+        assert!(mempool.insert(b2.hash(), &b2));
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.is_present(a1.hash()));
+        assert!(!mempool.is_present(a2.hash()));
+
+        // a transaction paying less than everything it would replace is not kept
+        let a2_cheap = signed(&alice, 10, 0, 2);
+        assert!(!mempool.insert(a2_cheap.hash(), &a2_cheap));
+        assert_eq!(mempool.len(), 3);
+    }
+
+    #[test]
+    fn deduplicates_on_txid_and_nonce() {
+        let alice = key_pair::random();
+        let tx = signed(&alice, 10, 1, 1);
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert(tx.hash(), &tx));
+
+        // the same transaction with another signature has the same txid
+        let resigned = SignedTransaction::new(tx.get_t(), vec![0; 64], alice.public_key().as_ref().to_vec());
+        assert_eq!(resigned.txid(), tx.txid());
+        assert_ne!(resigned.wtxid(), tx.wtxid());
+        assert!(!mempool.insert(resigned.hash(), &resigned));
+
+        // another transaction for the same sender and nonce is not kept either
+        let other = signed(&alice, 20, 1, 1);
+        assert_ne!(other.txid(), tx.txid());
+        assert!(!mempool.insert(other.hash(), &other));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn byte_limit_is_enforced() {
+        let alice = key_pair::random();
+        let tx = signed(&alice, 10, 1, 1);
+        let mut mempool = Mempool::with_limits(MAX_MEMPOOL_TXS, 2 * size_of(&tx));
+        for nonce in 1..=3 {
+            let tx = signed(&alice, 10, nonce, nonce);
+            mempool.insert(tx.hash(), &tx);
+        }
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.size_in_bytes(), 2 * size_of(&tx));
+
+        mempool.remove(tx.hash());
+        let remaining: Vec<H256> = mempool.get_mempool().keys().copied().collect();
+        for hash in remaining {
+            mempool.remove(hash);
+        }
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size_in_bytes(), 0);
+    }
+}
@@ -1,71 +1,79 @@
+pub mod difficulty;
+pub mod error;
+pub mod mempool;
+pub mod reorg;
+pub mod signature_cache;
+pub mod store;
+pub mod validation;
+
 use crate::types::hash::H256;
 use crate::types::hash::Hashable;
 use std::collections::HashMap;
+use std::io;
+use log::{info, warn, error};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use difficulty::RetargetParams;
+use error::BlockchainError;
+use reorg::ChainEvent;
+use signature_cache::SignatureCache;
+use store::{BlockStore, MemoryStore};
+pub use mempool::Mempool;
+use validation::BlockError;
 use super::types::block::{self, Block, Header, Content, State};
 use super::types::transaction::{self, Transaction, SignedTransaction};
 use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::uint::U256;
 use ring::signature::KeyPair;
 use ring::signature::Ed25519KeyPair;
 
 pub struct Blockchain {
     block_hash: HashMap<H256, Block>, // key = hash, value = block
     length_hash: HashMap<H256, u128>, // key = hash, value = length of block
-    tip: H256, // last block's hash in longest chain
-    longest_length: u128, // length of longest chain
+    genesis_hash: H256, // hash of the genesis block
+    tip: H256, // last block's hash in the chain with the most work
+    total_work: HashMap<H256, U256>, // key = hash, value = work of the block and all its ancestors
     blockchain_state: HashMap<H256, State>, // key = hash of block, value = state
+    store: Box<dyn BlockStore>, // backend every inserted block is written through to
+    subscribers: Vec<Sender<ChainEvent>>, // notified whenever the tip changes
+    retarget: RetargetParams, // how the difficulty adjusts to the block rate
+    signatures: SignatureCache, // transactions whose witness already verified
 }
 
-// structure to store received valid transactions not included blockchain yet
-pub struct Mempool {
-    mem_pool: HashMap<H256, SignedTransaction>,
-}
-
-impl Mempool {
-    // constructor
-    pub fn new() -> Self {
-        Self {mem_pool: HashMap::new()}
-    }
-
-    // checks if present
-    pub fn is_present(&self, hash: H256) -> bool {
-
-        let output = match self.mem_pool.get(&hash) {
-            None => false,
-            _ => true,
-        };
-
-        output
-    }
-
-    // get transaction
-    pub fn get_transaction(&self, hash: H256) -> SignedTransaction {
-        self.mem_pool.get(&hash).unwrap().clone()
-    }
-
-    // get mem_pool
-    pub fn get_mempool(&self) -> HashMap<H256, SignedTransaction> {
-        self.mem_pool.clone()
+impl Blockchain {
+    /// Create a new blockchain, only containing the genesis block
+    pub fn new(key_pair: Ed25519KeyPair) -> Self {
+        Self::genesis(key_pair, Box::new(MemoryStore))
     }
 
-    // insert transaction into hashmap
-    pub fn insert(&mut self, hash: H256, trans: &SignedTransaction) {
-        let transaction_clone = trans.clone();
-        self.mem_pool.insert(hash, transaction_clone); // ASK ABOUT THIS
-
-        // let block_hash = block.hash();
-        // let block_clone = block.clone();
-        // self.block_hash.insert(block_hash, block_clone);
-    }
+    /// Create a blockchain backed by a block store, replaying every block already in the store
+    pub fn with_store(key_pair: Ed25519KeyPair, mut store: Box<dyn BlockStore>) -> io::Result<Self> {
+        let blocks = store.load()?;
+        let mut blockchain = Self::genesis(key_pair, store);
+
+        let mut restored = 0;
+        for block in blocks.iter() {
+            if blockchain.is_present(block.hash()) {
+                continue;
+            }
+            // blocks are appended after their parent, so the parent is only unknown if the store
+            // was written by a node with a different genesis block
+            match blockchain.derive_state(block) {
+                Ok(state) => blockchain.insert_in_memory(block, state),
+                Err(e) => {
+                    warn!("Skipping stored block {}: {}", block.hash(), e);
+                    continue;
+                }
+            }
+            restored += 1;
+        }
+        info!("Restored {} blocks from block store, tip is {}", restored, blockchain.tip);
 
-    // remove transaction from hashmap
-    pub fn remove(&mut self, hash: H256) {
-        self.mem_pool.remove(&hash);
+        Ok(blockchain)
     }
-}
 
-impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
-    pub fn new(key_pair: Ed25519KeyPair) -> Self {
+    // build the genesis block and a blockchain containing only it
+    fn genesis(key_pair: Ed25519KeyPair, store: Box<dyn BlockStore>) -> Self {
         // MY CODE
 
         // create genesis block
@@ -78,17 +86,19 @@ impl Blockchain {
 
         // generate random key_pair and transaction -- CHECK THIS
         let receiver = Address::from_public_key_bytes(key_pair.public_key().as_ref());
-        let value = 1000000000; // I chose this
+        let value = Amount::new(1000000000); // I chose this
         let nonce = 1000000; // I chose this
 
         let public_key = key_pair.public_key().as_ref().to_vec();
-        let transaction = Transaction::new(receiver, value, nonce);
-        let sig = transaction::sign(&transaction, &key_pair).as_ref().to_vec();
+        let transaction = Transaction::new(receiver, receiver, value, Amount::ZERO, nonce as u64);
+        // the chain id is the hash of this block, so the genesis transaction is signed for none;
+        // it is never validated
+        let sig = transaction::sign(&transaction, &H256::default(), &key_pair).as_ref().to_vec();
         let signed_transaction = SignedTransaction::new(transaction, sig, public_key);
 
         // make state
         let mut state = State::new();
-        state.insert(receiver, nonce, value);
+        state.insert(receiver, nonce as u64, value);
 
         // make content
         let mut content_data: Vec<SignedTransaction> = Vec::new();
@@ -99,8 +109,8 @@ impl Blockchain {
         // FOR NOW, BUT NEED TO IMPLEMENT IN MERKLE.RS
         let merkle_root: H256 = H256::from(zeros); 
         
-        let header: Header = block::build_header(parent, nonce, difficulty, timestamp, merkle_root);
-        let genesis: Block = block::build_block(header, data, state.clone());
+        let header: Header = block::build_header(parent, nonce, difficulty, timestamp, merkle_root, state.root());
+        let genesis: Block = block::build_block(header, data);
         let genesis_hash = genesis.hash();
 
         // make blockchain state
@@ -114,20 +124,58 @@ impl Blockchain {
         let mut length_hash = HashMap::new();
         length_hash.insert(genesis_hash, 0);
         
+        let mut total_work = HashMap::new();
+        total_work.insert(genesis_hash, difficulty::work(&difficulty));
+
         let tip = genesis_hash;
-        let longest_length = 0;
 
-        Self {block_hash, length_hash, tip, longest_length, blockchain_state}
+        let subscribers = Vec::new();
+        let retarget = RetargetParams::default();
+        let signatures = SignatureCache::new();
+
+        Self {block_hash, length_hash, genesis_hash, tip, total_work, blockchain_state, store, subscribers, retarget, signatures}
     }
 
-    /// Insert a block into blockchain
+    /// Insert a block into blockchain, deriving its state from the parent's state
     pub fn insert(&mut self, block: &Block) {
+        if self.is_present(block.hash()) {
+            return;
+        }
+        let state = match self.derive_state(block) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Not inserting block {}: {}", block.hash(), e);
+                return;
+            }
+        };
+
+        // write through to the block store before updating the in-memory view
+        if let Err(e) = self.store.append(block) {
+            error!("Error writing block {} to block store: {}", block.hash(), e);
+        }
+
+        self.insert_in_memory(block, state);
+    }
+
+    // replay the content of a block on top of its parent's state
+    fn derive_state(&self, block: &Block) -> Result<State, BlockError> {
+        let parent_state = self.blockchain_state.get(&block.get_parent())
+            .ok_or_else(|| BlockError::UnknownParent(block.get_parent()))?;
+        let parent_height = self.length_hash.get(&block.get_parent())
+            .ok_or_else(|| BlockError::UnknownParent(block.get_parent()))?;
+        validation::apply_block(parent_state, block, parent_height + 1)
+    }
+
+    // insert a block into the in-memory maps and update the tip; callers derived the state from
+    // the parent's, so the parent is known
+    fn insert_in_memory(&mut self, block: &Block, state: State) {
 
         // insert block into block_hash
         
         let block_hash = block.hash();
         let block_clone = block.clone();
         self.block_hash.insert(block_hash, block_clone);
+        self.blockchain_state.insert(block_hash, state);
         
         // insert length of block into length_hash
         let parent = block.get_parent();
@@ -135,15 +183,95 @@ impl Blockchain {
         let block_length = parent_length + 1;
         
         self.length_hash.insert(block_hash, block_length);
-        
-        // update longest_length and tip if necessary
-        if block_length > self.longest_length {
-            self.longest_length = block_length;
+
+        let block_work = self.total_work[&parent].saturating_add(&difficulty::work(&block.get_difficulty()));
+        self.total_work.insert(block_hash, block_work);
+
+        // switch to the new block only if its chain has strictly more work, so that of two chains
+        // with equal work the one seen first stays the tip
+        if block_work > self.total_work[&self.tip] {
+            let old_tip = self.tip;
             self.tip = block_hash;
+            self.notify_tip_change(old_tip, block_hash);
         }
     }
 
-    /// Get the last block's hash of the longest chain
+    // tell every subscriber that the tip moved, and whether that abandoned part of the old chain
+    fn notify_tip_change(&mut self, old_tip: H256, new_tip: H256) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = match self.tip_change_event(old_tip, new_tip) {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Cannot tell subscribers about new tip {}: {}", new_tip, e);
+                return;
+            }
+        };
+
+        // drop subscribers that went away
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    fn tip_change_event(&self, old_tip: H256, new_tip: H256) -> Result<ChainEvent, BlockchainError> {
+        let ancestor = reorg::common_ancestor(self, old_tip, new_tip)?;
+        if ancestor == old_tip {
+            return Ok(ChainEvent::NewTip { old_tip, new_tip });
+        }
+        let depth = (self.height(old_tip)? - self.height(ancestor)?) as usize;
+        Ok(ChainEvent::Reorg { depth, old_tip, new_tip })
+    }
+
+    /// Subscribe to tip changes
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Set the difficulty retargeting parameters
+    pub fn set_retarget_params(&mut self, retarget: RetargetParams) {
+        self.retarget = retarget;
+    }
+
+    /// Get the difficulty retargeting parameters
+    pub fn retarget_params(&self) -> RetargetParams {
+        self.retarget
+    }
+
+    /// Get the easiest allowed difficulty target, which is the genesis block's
+    pub fn max_difficulty(&self) -> H256 {
+        // the genesis block is inserted on construction and never removed
+        self.block_hash[&self.genesis_hash].get_difficulty()
+    }
+
+    /// Get the hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    /// Get the id that transaction signatures commit to, so they do not replay on other chains:
+    /// the hash of the genesis block
+    pub fn chain_id(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    /// Get the transactions whose witness already verified on this chain
+    pub fn signature_cache(&self) -> &SignatureCache {
+        &self.signatures
+    }
+
+    /// Get the height of a block, genesis being at height 0
+    pub fn height(&self, hash: H256) -> Result<u128, BlockchainError> {
+        self.length_hash.get(&hash).copied().ok_or(BlockchainError::UnknownBlock(hash))
+    }
+
+    /// Get the cumulative work of a block and all its ancestors, which decides fork choice
+    pub fn total_work(&self, hash: H256) -> Result<U256, BlockchainError> {
+        self.total_work.get(&hash).copied().ok_or(BlockchainError::UnknownBlock(hash))
+    }
+
+    /// Get the last block's hash of the chain with the most work
     pub fn tip(&self) -> H256 {
         // MY CODE
         self.tip
@@ -151,38 +279,36 @@ impl Blockchain {
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let tip_hash = self.tip;
-        let block = self.block_hash[&tip_hash].clone();
-        let mut count = block.get_parent();
-
-        // create vec to store hashes
         let mut output: Vec<H256> = Vec::new();
-        
-        let zeros: [u8; 32] = [0; 32];
-        output.push(tip_hash);
-
-        while count != H256::from(zeros) {
-            output.push(count);
-            count = self.block_hash[&count].clone().get_parent();
-        }
- 
-        // reverse order of elements in vector
-        let length = output.len();
-        let half_length = &length / 2;
-    
-        for i in 0..(half_length) {
-            let temp = output[i];
-            output[i] = output[&length - 1 - i];
-            output[&length - 1 - i] = temp;
+        let mut count = Some(self.tip);
+
+        // every stored block's parent is stored too, up to the genesis block whose parent is zeros
+        while let Some(hash) = count {
+            output.push(hash);
+            count = self.block_hash.get(&hash)
+                .map(|block| block.get_parent())
+                .filter(|parent| self.block_hash.contains_key(parent));
         }
 
+        output.reverse();
         output
-        // vec![]
+    }
+
+    /// Get the hash of the block at this height in the longest chain
+    pub fn hash_at_height(&self, height: u128) -> Result<H256, BlockchainError> {
+        let chain = self.all_blocks_in_longest_chain();
+        let tip_height = chain.len() as u128 - 1;
+        chain.get(height as usize).copied().ok_or(BlockchainError::HeightOutOfRange { height, tip_height })
+    }
+
+    /// Get the state after applying the block with this hash
+    pub fn get_state(&self, hash: H256) -> Result<State, BlockchainError> {
+        self.blockchain_state.get(&hash).cloned().ok_or(BlockchainError::UnknownBlock(hash))
     }
 
     // get parent_block
-    pub fn get_parent_block(&self, parent: H256) -> Block {
-        self.block_hash.get(&parent).unwrap().clone()
+    pub fn get_parent_block(&self, parent: H256) -> Result<Block, BlockchainError> {
+        self.block_hash.get(&parent).cloned().ok_or(BlockchainError::UnknownBlock(parent))
     }
 
     // check if block with certain hash present in hashmap
@@ -228,6 +354,80 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn missing_entries_are_errors() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::new(key_pair);
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+
+        let unknown = H256::from([7; 32]);
+        assert_eq!(blockchain.get_parent_block(unknown).unwrap_err(), BlockchainError::UnknownBlock(unknown));
+        assert_eq!(blockchain.get_state(unknown).unwrap_err(), BlockchainError::UnknownBlock(unknown));
+        assert_eq!(blockchain.height(unknown).unwrap_err(), BlockchainError::UnknownBlock(unknown));
+        assert_eq!(blockchain.total_work(unknown).unwrap_err(), BlockchainError::UnknownBlock(unknown));
+
+        assert_eq!(blockchain.hash_at_height(1), Ok(block.hash()));
+        assert_eq!(blockchain.hash_at_height(2), Err(BlockchainError::HeightOutOfRange { height: 2, tip_height: 1 }));
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis_hash, block.hash()]);
+
+        let mempool = Mempool::new();
+        assert_eq!(mempool.get_transaction(unknown).unwrap_err(), BlockchainError::UnknownTransaction(unknown));
+    }
+
+    // empty block on top of parent with the given target, without proof of work since insert does not check it
+    fn block_with_difficulty(parent: H256, difficulty: [u8; 32]) -> Block {
+        let header = block::build_header(parent, rand::random(), difficulty.into(), 0, H256::from([0; 32]), H256::from([0; 32]));
+        block::build_block(header, block::build_content(vec![]))
+    }
+
+    #[test]
+    fn most_work_wins_over_most_blocks() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::new(key_pair);
+        let genesis_hash = blockchain.tip();
+
+        // three easy blocks, one unit of work each
+        let mut easy = genesis_hash;
+        for _ in 0..3 {
+            let block = block_with_difficulty(easy, [0xff; 32]);
+            blockchain.insert(&block);
+            easy = block.hash();
+        }
+        assert_eq!(blockchain.tip(), easy);
+        assert_eq!(blockchain.total_work(easy).unwrap(), blockchain.total_work(genesis_hash).unwrap().saturating_add(&U256::from(3)));
+
+        // a single block with a target four times as hard outweighs them
+        let mut hard_target = [0xff; 32];
+        hard_target[0] = 0x3f;
+        let hard = block_with_difficulty(genesis_hash, hard_target);
+        blockchain.insert(&hard);
+        assert_eq!(blockchain.tip(), hard.hash());
+        assert!(blockchain.height(hard.hash()).unwrap() < blockchain.height(easy).unwrap());
+    }
+
+    #[test]
+    fn equal_work_keeps_first_seen_tip() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::new(key_pair);
+        let genesis_hash = blockchain.tip();
+
+        let first = block_with_difficulty(genesis_hash, [0xff; 32]);
+        let second = block_with_difficulty(genesis_hash, [0xff; 32]);
+        blockchain.insert(&first);
+        blockchain.insert(&second);
+        assert_eq!(blockchain.total_work(first.hash()), blockchain.total_work(second.hash()));
+        assert_eq!(blockchain.tip(), first.hash());
+
+        // the same holds with the insertion order reversed
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::new(key_pair);
+        blockchain.insert(&second);
+        blockchain.insert(&first);
+        assert_eq!(blockchain.tip(), second.hash());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
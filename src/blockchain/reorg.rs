@@ -0,0 +1,206 @@
+use crate::blockchain::{Blockchain, Mempool};
+use crate::blockchain::error::BlockchainError;
+use crate::miner::Handle as MinerHandle;
+use crate::txgen::Handle as TxHandle;
+use crate::types::hash::{H256, Hashable};
+use crossbeam::channel::Receiver;
+use log::{debug, error, info};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Change of the canonical chain, sent to every subscriber of `Blockchain::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// The new tip extends the old tip
+    NewTip { old_tip: H256, new_tip: H256 },
+    /// The new tip is on a different branch; `depth` blocks of the old branch were abandoned
+    Reorg { depth: usize, old_tip: H256, new_tip: H256 },
+}
+
+/// Find the last block that two blocks have in common
+pub fn common_ancestor(blockchain: &Blockchain, a: H256, b: H256) -> Result<H256, BlockchainError> {
+    let mut a = a;
+    let mut b = b;
+
+    // first bring both to the same height, then walk back together
+    while blockchain.height(a)? > blockchain.height(b)? {
+        a = blockchain.get_parent_block(a)?.get_parent();
+    }
+    while blockchain.height(b)? > blockchain.height(a)? {
+        b = blockchain.get_parent_block(b)?.get_parent();
+    }
+    while a != b {
+        a = blockchain.get_parent_block(a)?.get_parent();
+        b = blockchain.get_parent_block(b)?.get_parent();
+    }
+    Ok(a)
+}
+
+/// Hashes of the blocks after `ancestor` up to and including `tip`, ordered from `ancestor` to `tip`
+pub fn branch(blockchain: &Blockchain, ancestor: H256, tip: H256) -> Result<Vec<H256>, BlockchainError> {
+    let mut output: Vec<H256> = Vec::new();
+    let mut count = tip;
+    while count != ancestor {
+        output.push(count);
+        count = blockchain.get_parent_block(count)?.get_parent();
+    }
+    output.reverse();
+    Ok(output)
+}
+
+/// Bring the mempool in line with a tip change: transactions of the abandoned branch go back in,
+/// transactions included in the new branch are evicted, and the rest is pruned against the new
+/// tip state.
+pub fn update_mempool(blockchain: &Blockchain, mempool: &mut Mempool, old_tip: H256, new_tip: H256) -> Result<(), BlockchainError> {
+    let ancestor = common_ancestor(blockchain, old_tip, new_tip)?;
+
+    let mut included: HashSet<H256> = HashSet::new();
+    for hash in branch(blockchain, ancestor, new_tip)? {
+        for tx in blockchain.get_parent_block(hash)?.get_content() {
+            included.insert(tx.hash());
+            mempool.remove(tx.hash());
+        }
+    }
+
+    for hash in branch(blockchain, ancestor, old_tip)? {
+        for tx in blockchain.get_parent_block(hash)?.get_content() {
+            // a coinbase is only valid in the block that mined it
+            if !tx.is_coinbase() && !included.contains(&tx.hash()) {
+                mempool.insert(tx.hash(), &tx);
+            }
+        }
+    }
+
+    mempool.prune(&blockchain.get_state(new_tip)?);
+    Ok(())
+}
+
+/// Consumes chain events: keeps the mempool consistent with the canonical chain and tells the
+/// miner and transaction generator that the tip changed.
+pub struct Worker {
+    events: Receiver<ChainEvent>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
+    tx_gen: TxHandle,
+}
+
+impl Worker {
+    pub fn new(
+        events: Receiver<ChainEvent>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
+        tx_gen: &TxHandle,
+    ) -> Self {
+        Self {
+            events,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            miner: miner.clone(),
+            tx_gen: tx_gen.clone(),
+        }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("reorg-worker".to_string())
+            .spawn(move || {
+                self.worker_loop();
+            })
+            .unwrap();
+        info!("Reorg worker started");
+    }
+
+    fn worker_loop(&self) {
+        while let Ok(event) = self.events.recv() {
+            let (old_tip, new_tip) = match event {
+                ChainEvent::NewTip { old_tip, new_tip } => {
+                    debug!("New tip {}", new_tip);
+                    (old_tip, new_tip)
+                }
+                ChainEvent::Reorg { depth, old_tip, new_tip } => {
+                    info!("Reorg of depth {} from {} to {}", depth, old_tip, new_tip);
+                    (old_tip, new_tip)
+                }
+            };
+
+            {
+                let blockchain = self.blockchain.lock().unwrap();
+                let mut mempool = self.mempool.lock().unwrap();
+                if let Err(e) = update_mempool(&blockchain, &mut mempool, old_tip, new_tip) {
+                    error!("Cannot update mempool for new tip {}: {}", new_tip, e);
+                }
+            }
+
+            self.miner.update();
+            self.tx_gen.update();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+    use crate::types::block::{self, Block, State};
+    use crate::types::transaction::{self, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn genesis_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap()
+    }
+
+    // block on top of parent, without proof of work since insert does not check it
+    fn child(blockchain: &Blockchain, parent: H256, content: Vec<SignedTransaction>) -> Block {
+        let parent_block = blockchain.get_parent_block(parent).unwrap();
+        let state: State = crate::blockchain::validation::apply_transactions(&blockchain.get_state(parent).unwrap(), &content).unwrap();
+        let merkle_root = block::merkle_root(&content);
+        let header = block::build_header(parent, rand::random(), parent_block.get_difficulty(), parent_block.get_timestamp() + 1, merkle_root, state.root());
+        block::build_block(header, block::build_content(content))
+    }
+
+    #[test]
+    fn reorg_reinjects_abandoned_transactions() {
+        let key = genesis_key();
+        let mut blockchain = Blockchain::new(genesis_key());
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let (nonce, _) = blockchain.get_state(genesis_hash).unwrap().get(sender).unwrap();
+
+        let t = Transaction::new(sender, [1u8; 20].into(), Amount::new(5), Amount::ZERO, nonce + 1);
+        let sig = transaction::sign(&t, &blockchain.chain_id(), &key).as_ref().to_vec();
+        let tx = SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec());
+
+        // main chain carries the transaction
+        let a1 = child(&blockchain, genesis_hash, vec![tx.clone()]);
+        blockchain.insert(&a1);
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::NewTip { old_tip: genesis_hash, new_tip: a1.hash() });
+
+        // a longer fork without it takes over
+        let b1 = child(&blockchain, genesis_hash, vec![]);
+        blockchain.insert(&b1);
+        assert!(events.try_recv().is_err()); // same length, first seen wins
+        let b2 = child(&blockchain, b1.hash(), vec![]);
+        blockchain.insert(&b2);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event, ChainEvent::Reorg { depth: 1, old_tip: a1.hash(), new_tip: b2.hash() });
+        assert_eq!(common_ancestor(&blockchain, a1.hash(), b2.hash()).unwrap(), genesis_hash);
+        assert_eq!(branch(&blockchain, genesis_hash, b2.hash()).unwrap(), vec![b1.hash(), b2.hash()]);
+
+        let mut mempool = Mempool::new();
+        update_mempool(&blockchain, &mut mempool, a1.hash(), b2.hash()).unwrap();
+        assert!(mempool.is_present(tx.hash()));
+
+        // switching back evicts it again
+        let a2 = child(&blockchain, a1.hash(), vec![]);
+        blockchain.insert(&a2);
+        let a3 = child(&blockchain, a2.hash(), vec![]);
+        blockchain.insert(&a3);
+        update_mempool(&blockchain, &mut mempool, b2.hash(), a3.hash()).unwrap();
+        assert!(!mempool.is_present(tx.hash()));
+    }
+}
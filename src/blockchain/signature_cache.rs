@@ -0,0 +1,86 @@
+use crate::types::hash::H256;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Most transactions remembered as verified
+pub const SIGNATURE_CACHE_SIZE: usize = 50_000;
+
+/// Transactions whose witness already verified on this chain, keyed by wtxid.
+///
+/// A transaction is verified once when it enters the mempool, and validating a block carrying it
+/// later skips its signatures. The wtxid covers the witness, so a transaction re-signed with a bad
+/// signature is not mistaken for the verified one. The oldest entries are dropped first.
+pub struct SignatureCache {
+    inner: Mutex<Entries>,
+    capacity: usize,
+}
+
+struct Entries {
+    verified: HashSet<H256>,
+    order: VecDeque<H256>, // oldest first
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignatureCache {
+    pub fn new() -> Self {
+        Self::with_capacity(SIGNATURE_CACHE_SIZE)
+    }
+
+    /// Create a cache remembering at most `capacity` transactions
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Entries { verified: HashSet::new(), order: VecDeque::new() }),
+            capacity,
+        }
+    }
+
+    /// Check whether the witness of a transaction already verified
+    pub fn contains(&self, wtxid: &H256) -> bool {
+        self.inner.lock().unwrap().verified.contains(wtxid)
+    }
+
+    /// Remember that the witness of a transaction verified
+    pub fn insert(&self, wtxid: H256) {
+        let mut entries = self.inner.lock().unwrap();
+        if self.capacity == 0 || !entries.verified.insert(wtxid) {
+            return;
+        }
+        entries.order.push_back(wtxid);
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.verified.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().verified.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_oldest_entries() {
+        let cache = SignatureCache::with_capacity(2);
+        for i in 1..=3u8 {
+            cache.insert(H256::from([i; 32]));
+        }
+        cache.insert(H256::from([3; 32]));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&H256::from([1; 32])));
+        assert!(cache.contains(&H256::from([2; 32])));
+        assert!(cache.contains(&H256::from([3; 32])));
+    }
+}
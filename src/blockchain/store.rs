@@ -0,0 +1,349 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Storage backend that the blockchain writes every inserted block through to.
+pub trait BlockStore: Send {
+    /// Persist a block that was just inserted into the blockchain
+    fn append(&mut self, block: &Block) -> io::Result<()>;
+
+    /// Load every stored block, in the order the blocks were appended
+    fn load(&mut self) -> io::Result<Vec<Block>>;
+}
+
+/// Store that keeps nothing, so the blockchain starts from genesis every time.
+#[derive(Default)]
+pub struct MemoryStore;
+
+impl BlockStore for MemoryStore {
+    fn append(&mut self, _block: &Block) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        Ok(Vec::new())
+    }
+}
+
+// size of one index record: 32-byte hash followed by 8-byte log offset
+const INDEX_RECORD_SIZE: usize = 40;
+
+/// Append-only block log plus an index from block hash to log offset.
+///
+/// The log (`blocks.log`) holds one record per block: a 4-byte big endian length followed by the
+/// bincode encoding of the block. The index (`blocks.idx`) holds one record per block: the block
+/// hash followed by the 8-byte big endian offset of its log record. On open the offsets come from
+/// the index, each checked against the hash of the block it points at, and only the log past the
+/// last matching record is scanned, so a short, corrupt or stale index is completed from the log. A
+/// torn record at the end of the log (e.g. after a crash) is truncated.
+pub struct FileStore {
+    log: File,
+    index_file: File,
+    index: HashMap<H256, u64>,
+    order: Vec<H256>, // block hashes in append order
+}
+
+impl FileStore {
+    /// Open (or create) the block store inside `dir`
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut log = OpenOptions::new().read(true).append(true).create(true).open(dir.join("blocks.log"))?;
+        let mut index_file = OpenOptions::new().read(true).append(true).create(true).open(dir.join("blocks.idx"))?;
+
+        // take the offsets from the index, up to the first record that does not match the log
+        let mut index_bytes = Vec::new();
+        index_file.seek(SeekFrom::Start(0))?;
+        index_file.read_to_end(&mut index_bytes)?;
+        let (mut records, log_start) = Self::indexed_records(&mut log, &index_bytes)?;
+
+        // scan the rest of the log to find the complete records the index misses
+        let mut bytes = Vec::new();
+        log.seek(SeekFrom::Start(log_start))?;
+        log.read_to_end(&mut bytes)?;
+        let mut offset: usize = 0;
+        while offset + 4 <= bytes.len() {
+            let size = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + size > bytes.len() {
+                break;
+            }
+            let block: Block = match bincode::deserialize(&bytes[offset + 4..offset + 4 + size]) {
+                Ok(b) => b,
+                Err(_) => break,
+            };
+            records.push((block.hash(), log_start + offset as u64));
+            offset += 4 + size;
+        }
+        if offset < bytes.len() {
+            // drop the torn tail so that new records are appended after the last good one
+            log.set_len(log_start + offset as u64)?;
+        }
+
+        // rewrite the index file if it does not match the log
+        if index_bytes != Self::encode_index(&records) {
+            index_file.set_len(0)?;
+            index_file.write_all(&Self::encode_index(&records))?;
+            index_file.sync_data()?;
+        }
+
+        let index = records.iter().cloned().collect();
+        let order = records.into_iter().map(|(hash, _)| hash).collect();
+
+        Ok(Self {log, index_file, index, order})
+    }
+
+    // decode the index records that point at consecutive log records from the start of the log
+    // holding the block they name, stopping at the first one that does not; also returns the
+    // offset just past the last of those records
+    fn indexed_records(log: &mut File, index_bytes: &[u8]) -> io::Result<(Vec<(H256, u64)>, u64)> {
+        let log_len = log.metadata()?.len();
+        let mut records: Vec<(H256, u64)> = Vec::new();
+        let mut seen: HashMap<H256, u64> = HashMap::new();
+        let mut expected: u64 = 0;
+        for chunk in index_bytes.chunks_exact(INDEX_RECORD_SIZE) {
+            let hash_bytes: [u8; 32] = chunk[..32].try_into().unwrap();
+            let hash = H256::from(hash_bytes);
+            let offset = u64::from_be_bytes(chunk[32..].try_into().unwrap());
+            if offset != expected || seen.insert(hash, offset).is_some() {
+                break;
+            }
+            // a stale index may point at a complete record of another block
+            match Self::record_at(log, offset, log_len)? {
+                Some((stored, end)) if stored == hash => {
+                    records.push((hash, offset));
+                    expected = end;
+                }
+                _ => break,
+            }
+        }
+        Ok((records, expected))
+    }
+
+    // hash of the block in the log record at `offset` and the offset just past the record, or
+    // none if the record is incomplete or does not decode
+    fn record_at(log: &mut File, offset: u64, log_len: u64) -> io::Result<Option<(H256, u64)>> {
+        if offset + 4 > log_len {
+            return Ok(None);
+        }
+        let mut size_buffer = [0u8; 4];
+        log.seek(SeekFrom::Start(offset))?;
+        log.read_exact(&mut size_buffer)?;
+        let end = offset + 4 + u32::from_be_bytes(size_buffer) as u64;
+        if end > log_len {
+            return Ok(None);
+        }
+        let mut block_buffer = vec![0u8; (end - offset - 4) as usize];
+        log.read_exact(&mut block_buffer)?;
+        Ok(bincode::deserialize::<Block>(&block_buffer).ok().map(|block| (block.hash(), end)))
+    }
+
+    // encode (hash, offset) pairs in the on-disk index format
+    fn encode_index(records: &[(H256, u64)]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(records.len() * INDEX_RECORD_SIZE);
+        for (hash, offset) in records {
+            output.extend_from_slice(hash.as_ref());
+            output.extend_from_slice(&offset.to_be_bytes());
+        }
+        output
+    }
+
+    /// Check whether a block with this hash is stored
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Read a single block by hash using the index
+    pub fn get(&mut self, hash: &H256) -> io::Result<Option<Block>> {
+        let offset = match self.index.get(hash) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        self.log.seek(SeekFrom::Start(offset))?;
+        let mut size_buffer = [0u8; 4];
+        self.log.read_exact(&mut size_buffer)?;
+        let mut block_buffer = vec![0u8; u32::from_be_bytes(size_buffer) as usize];
+        self.log.read_exact(&mut block_buffer)?;
+        let block = bincode::deserialize(&block_buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(block))
+    }
+
+    /// Number of stored blocks
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl BlockStore for FileStore {
+    fn append(&mut self, block: &Block) -> io::Result<()> {
+        let hash = block.hash();
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+
+        let encoded = bincode::serialize(block).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let offset = self.log.seek(SeekFrom::End(0))?;
+        let mut record = Vec::with_capacity(4 + encoded.len());
+        record.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        record.extend_from_slice(&encoded);
+        self.log.write_all(&record)?;
+        self.log.sync_data()?;
+
+        self.index_file.write_all(&Self::encode_index(&[(hash, offset)]))?;
+        self.index_file.sync_data()?;
+
+        self.index.insert(hash, offset);
+        self.order.push(hash);
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<Block>> {
+        let mut blocks = Vec::with_capacity(self.order.len());
+        for hash in self.order.clone() {
+            if let Some(block) = self.get(&hash)? {
+                blocks.push(block);
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("bitcoin-store-{}-{}", name, generate_random_hash()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = temp_dir("reopen");
+        let block_1 = generate_random_block(&generate_random_hash());
+        let block_2 = generate_random_block(&block_1.hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.append(&block_1).unwrap();
+            store.append(&block_2).unwrap();
+            store.append(&block_1).unwrap(); // duplicate is ignored
+        }
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 2);
+        let loaded: Vec<H256> = store.load().unwrap().iter().map(|b| b.hash()).collect();
+        assert_eq!(loaded, vec![block_1.hash(), block_2.hash()]);
+        assert_eq!(store.get(&block_2.hash()).unwrap().unwrap().hash(), block_2.hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let dir = temp_dir("torn");
+        let block_1 = generate_random_block(&generate_random_hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.append(&block_1).unwrap();
+        }
+        // simulate a crash in the middle of writing a record
+        let mut log = OpenOptions::new().append(true).open(dir.join("blocks.log")).unwrap();
+        log.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        drop(log);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1);
+        let block_2 = generate_random_block(&block_1.hash());
+        store.append(&block_2).unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded: Vec<H256> = store.load().unwrap().iter().map(|b| b.hash()).collect();
+        assert_eq!(loaded, vec![block_1.hash(), block_2.hash()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_or_corrupt_index_is_repaired() {
+        let dir = temp_dir("index");
+        let block_1 = generate_random_block(&generate_random_hash());
+        let block_2 = generate_random_block(&block_1.hash());
+        let block_3 = generate_random_block(&block_2.hash());
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.append(&block_1).unwrap();
+            store.append(&block_2).unwrap();
+            store.append(&block_3).unwrap();
+        }
+        let index_path = dir.join("blocks.idx");
+        let full_index = fs::read(&index_path).unwrap();
+        assert_eq!(full_index.len(), 3 * INDEX_RECORD_SIZE);
+        let expected = vec![block_1.hash(), block_2.hash(), block_3.hash()];
+
+        // an index cut in the middle of a record, as after a crash between the two writes
+        fs::write(&index_path, &full_index[..INDEX_RECORD_SIZE + 10]).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded: Vec<H256> = store.load().unwrap().iter().map(|b| b.hash()).collect();
+        assert_eq!(loaded, expected);
+        drop(store);
+        assert_eq!(fs::read(&index_path).unwrap(), full_index);
+
+        // an index whose second offset points into the middle of a record
+        let mut corrupt = full_index.clone();
+        corrupt[2 * INDEX_RECORD_SIZE - 1] ^= 1;
+        fs::write(&index_path, &corrupt).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        let loaded: Vec<H256> = store.load().unwrap().iter().map(|b| b.hash()).collect();
+        assert_eq!(loaded, expected);
+        drop(store);
+        assert_eq!(fs::read(&index_path).unwrap(), full_index);
+
+        // a stale index naming the wrong block at a valid offset
+        let mut stale = full_index.clone();
+        stale[INDEX_RECORD_SIZE..INDEX_RECORD_SIZE + 32].copy_from_slice(block_3.hash().as_ref());
+        stale[2 * INDEX_RECORD_SIZE..2 * INDEX_RECORD_SIZE + 32].copy_from_slice(block_2.hash().as_ref());
+        fs::write(&index_path, &stale).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.get(&block_2.hash()).unwrap().unwrap().hash(), block_2.hash());
+        assert_eq!(store.get(&block_3.hash()).unwrap().unwrap().hash(), block_3.hash());
+        drop(store);
+        assert_eq!(fs::read(&index_path).unwrap(), full_index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn blockchain_restores_from_store() {
+        use crate::blockchain::Blockchain;
+        use ring::signature::Ed25519KeyPair;
+
+        let dir = temp_dir("blockchain");
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let mut blockchain = Blockchain::with_store(key_pair, Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        let block_2 = generate_random_block(&block_1.hash());
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        blockchain.insert(&block_2);
+        blockchain.insert(&fork);
+        let longest = blockchain.all_blocks_in_longest_chain();
+        drop(blockchain);
+
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let blockchain = Blockchain::with_store(key_pair, Box::new(FileStore::open(&dir).unwrap())).unwrap();
+        assert_eq!(blockchain.tip(), block_2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), longest);
+        assert!(blockchain.is_present(fork.hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
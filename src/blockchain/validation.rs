@@ -0,0 +1,627 @@
+use crate::blockchain::{difficulty, Blockchain};
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::block::{self, Block, State};
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{self, SignedTransaction};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far ahead of the local clock a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Value a block's coinbase may mint on top of the fees of its transactions
+pub const BLOCK_REWARD: Amount = Amount::new(50);
+
+/// Reasons a single transaction cannot be applied to a state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The witness does not verify on this chain
+    InvalidSignature,
+    /// The sender address is not the one of the signing key or multisig account
+    SenderMismatch { expected: Address, found: Address },
+    /// The sender has no account in the state
+    UnknownSender(Address),
+    /// The transaction nonce is not the sender's account nonce plus one
+    InvalidNonce { expected: u64, found: u64 },
+    /// The sender cannot afford the transferred value plus the fee
+    InsufficientBalance { balance: Amount, cost: Amount },
+    /// An amount or nonce would not fit in 64 bits
+    Overflow,
+    /// A coinbase transaction anywhere but first in the block
+    UnexpectedCoinbase,
+}
+
+/// Reasons a block is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is already in the blockchain
+    AlreadyKnown,
+    /// The parent block is not in the blockchain (yet)
+    UnknownParent(H256),
+    /// The block hash is above its difficulty target
+    InsufficientProofOfWork,
+    /// The difficulty target is not the one expected after the parent
+    DifficultyMismatch { expected: H256, found: H256 },
+    /// The timestamp is not after the parent's timestamp
+    TimestampTooOld { parent: u128, found: u128 },
+    /// The timestamp is too far ahead of the local clock
+    TimestampTooNew { now: u128, found: u128 },
+    /// The merkle root in the header does not match the content
+    MerkleRootMismatch { expected: H256, found: H256 },
+    /// The same transaction appears twice in the content, which an odd merkle row hides
+    DuplicateTransaction { index: usize, hash: H256 },
+    /// A transaction in the content cannot be applied
+    InvalidTransaction { index: usize, hash: H256, error: TransactionError },
+    /// The state root in the header does not match the replayed post-state
+    StateRootMismatch { expected: H256, found: H256 },
+    /// The coinbase nonce is not the height of the block
+    CoinbaseHeightMismatch { expected: u64, found: u64 },
+    /// The coinbase pays more than the block reward plus fees
+    ExcessiveCoinbase { allowed: Amount, found: Amount },
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::SenderMismatch { expected, found } => {
+                write!(f, "sender {} does not match public key address {}", found, expected)
+            }
+            TransactionError::UnknownSender(address) => write!(f, "unknown sender {}", address),
+            TransactionError::InvalidNonce { expected, found } => {
+                write!(f, "invalid nonce {}, expected {}", found, expected)
+            }
+            TransactionError::InsufficientBalance { balance, cost } => {
+                write!(f, "insufficient balance {} to pay {}", balance, cost)
+            }
+            TransactionError::Overflow => write!(f, "amount or nonce overflow"),
+            TransactionError::UnexpectedCoinbase => write!(f, "coinbase is not the first transaction"),
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block already known"),
+            BlockError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            BlockError::InsufficientProofOfWork => write!(f, "block hash above difficulty target"),
+            BlockError::DifficultyMismatch { expected, found } => {
+                write!(f, "difficulty {} does not match expected {}", found, expected)
+            }
+            BlockError::TimestampTooOld { parent, found } => {
+                write!(f, "timestamp {} not after parent timestamp {}", found, parent)
+            }
+            BlockError::TimestampTooNew { now, found } => {
+                write!(f, "timestamp {} too far ahead of local time {}", found, now)
+            }
+            BlockError::MerkleRootMismatch { expected, found } => {
+                write!(f, "merkle root {} does not match content root {}", found, expected)
+            }
+            BlockError::DuplicateTransaction { index, hash } => {
+                write!(f, "transaction {} ({}) appears earlier in the block", index, hash)
+            }
+            BlockError::InvalidTransaction { index, hash, error } => {
+                write!(f, "transaction {} ({}) invalid: {}", index, hash, error)
+            }
+            BlockError::StateRootMismatch { expected, found } => {
+                write!(f, "state root {} does not match replayed state root {}", found, expected)
+            }
+            BlockError::CoinbaseHeightMismatch { expected, found } => {
+                write!(f, "coinbase height {} does not match block height {}", found, expected)
+            }
+            BlockError::ExcessiveCoinbase { allowed, found } => {
+                write!(f, "coinbase mints {}, more than the allowed {}", found, allowed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl std::error::Error for BlockError {}
+
+/// Check a transaction against a state without changing it, including its signature for the
+/// chain identified by `chain_id`
+pub fn validate_transaction(state: &State, tx: &SignedTransaction, chain_id: &H256) -> Result<(), TransactionError> {
+    if !transaction::verify_witness(tx, chain_id) {
+        return Err(TransactionError::InvalidSignature);
+    }
+    check_transaction(state, tx)
+}
+
+/// Check a transaction against a state without changing it, assuming its signature was verified
+pub fn check_transaction(state: &State, tx: &SignedTransaction) -> Result<(), TransactionError> {
+    check_spending(state, tx).map(|_| ())
+}
+
+/// Apply a signed transaction to a state, leaving the state untouched if it is invalid.
+///
+/// The signature is not checked here; use `validate_transaction` for untrusted transactions.
+pub fn apply_transaction(state: &mut State, tx: &SignedTransaction) -> Result<(), TransactionError> {
+    let (sender, sender_nonce, sender_balance) = check_spending(state, tx)?;
+    let t = tx.get_t();
+
+    // the sender is debited first, so that a transfer to oneself reads the updated balance; the
+    // fee leaves circulation. If receiver is new, make a new account
+    let receiver = t.get_receiver();
+    let (receiver_nonce, receiver_balance) = if receiver == sender {
+        (sender_nonce, sender_balance)
+    } else {
+        state.get(receiver).unwrap_or((0, Amount::ZERO))
+    };
+    let receiver_balance = receiver_balance.checked_add(t.get_value()).ok_or(TransactionError::Overflow)?;
+
+    state.insert(sender, sender_nonce, sender_balance);
+    state.insert(receiver, receiver_nonce, receiver_balance);
+
+    Ok(())
+}
+
+// spending check: sender matches the key, exists, nonce is next, balance is enough; returns the
+// sender with its nonce and balance after the transaction
+fn check_spending(state: &State, tx: &SignedTransaction) -> Result<(Address, u64, Amount), TransactionError> {
+    let t = tx.get_t();
+    let sender = t.get_sender();
+    let key_address = tx.get_witness().address().ok_or(TransactionError::UnexpectedCoinbase)?;
+    if sender != key_address {
+        return Err(TransactionError::SenderMismatch { expected: key_address, found: sender });
+    }
+    let (sender_nonce, sender_balance) = state.get(sender).ok_or(TransactionError::UnknownSender(sender))?;
+    let next_nonce = sender_nonce.checked_add(1).ok_or(TransactionError::Overflow)?;
+    if t.get_nonce() != next_nonce {
+        return Err(TransactionError::InvalidNonce { expected: next_nonce, found: t.get_nonce() });
+    }
+    let cost = t.get_value().checked_add(t.get_fee()).ok_or(TransactionError::Overflow)?;
+    let remaining = sender_balance.checked_sub(cost)
+        .ok_or(TransactionError::InsufficientBalance { balance: sender_balance, cost })?;
+    Ok((sender, next_nonce, remaining))
+}
+
+/// Replay transactions on top of the parent state, returning the post-state
+pub fn apply_transactions(parent_state: &State, content: &[SignedTransaction]) -> Result<State, BlockError> {
+    let mut state = parent_state.clone();
+    for (index, tx) in content.iter().enumerate() {
+        apply_transaction(&mut state, tx)
+            .map_err(|error| BlockError::InvalidTransaction { index, hash: tx.hash(), error })?;
+    }
+    Ok(state)
+}
+
+/// Replay the content of a block at `height` on top of the parent state, returning the post-state.
+///
+/// An optional coinbase comes first; it must carry the block height as its nonce and may pay its
+/// receiver up to the block reward plus the fees of the other transactions.
+pub fn apply_block_content(parent_state: &State, content: &[SignedTransaction], height: u128) -> Result<State, BlockError> {
+    let mut state = parent_state.clone();
+    let mut fees = Amount::ZERO;
+    for (index, tx) in content.iter().enumerate() {
+        if tx.is_coinbase() {
+            if index > 0 {
+                return Err(BlockError::InvalidTransaction { index, hash: tx.hash(), error: TransactionError::UnexpectedCoinbase });
+            }
+            continue;
+        }
+        apply_transaction(&mut state, tx)
+            .map_err(|error| BlockError::InvalidTransaction { index, hash: tx.hash(), error })?;
+        fees = fees.checked_add(tx.get_t().get_fee())
+            .ok_or(BlockError::InvalidTransaction { index, hash: tx.hash(), error: TransactionError::Overflow })?;
+    }
+
+    if let Some(coinbase) = content.first().filter(|tx| tx.is_coinbase()) {
+        let t = coinbase.get_t();
+        let overflow = BlockError::InvalidTransaction { index: 0, hash: coinbase.hash(), error: TransactionError::Overflow };
+        if t.get_nonce() as u128 != height {
+            return Err(BlockError::CoinbaseHeightMismatch { expected: height as u64, found: t.get_nonce() });
+        }
+        let allowed = BLOCK_REWARD.checked_add(fees).ok_or_else(|| overflow.clone())?;
+        if t.get_value() > allowed {
+            return Err(BlockError::ExcessiveCoinbase { allowed, found: t.get_value() });
+        }
+        let receiver = t.get_receiver();
+        let (receiver_nonce, receiver_balance) = state.get(receiver).unwrap_or((0, Amount::ZERO));
+        let receiver_balance = receiver_balance.checked_add(t.get_value()).ok_or(overflow)?;
+        state.insert(receiver, receiver_nonce, receiver_balance);
+    }
+    Ok(state)
+}
+
+/// Replay the content of a block at `height` on top of the parent state, returning the post-state
+pub fn apply_block(parent_state: &State, block: &Block, height: u128) -> Result<State, BlockError> {
+    apply_block_content(parent_state, &block.get_content(), height)
+}
+
+/// Validate a block against the blockchain it is about to be inserted into.
+///
+/// Every insertion path (blocks from peers, orphans, blocks mined locally) runs this before
+/// `Blockchain::insert`, so a malformed block is rejected the same way wherever it came from.
+pub fn validate_block(blockchain: &Blockchain, block: &Block) -> Result<(), BlockError> {
+    let hash = block.hash();
+    if blockchain.is_present(hash) {
+        return Err(BlockError::AlreadyKnown);
+    }
+
+    // PoW check
+    if hash > block.get_difficulty() {
+        return Err(BlockError::InsufficientProofOfWork);
+    }
+
+    // parent check
+    let parent_hash = block.get_parent();
+    let parent = blockchain.get_parent_block(parent_hash)
+        .map_err(|_| BlockError::UnknownParent(parent_hash))?;
+
+    // check difficulty in block header consistent with view, including retargeting
+    let expected_difficulty = difficulty::next_difficulty(blockchain, parent_hash)
+        .map_err(|_| BlockError::UnknownParent(parent_hash))?;
+    if block.get_difficulty() != expected_difficulty {
+        return Err(BlockError::DifficultyMismatch { expected: expected_difficulty, found: block.get_difficulty() });
+    }
+
+    // timestamp check
+    if block.get_timestamp() <= parent.get_timestamp() {
+        return Err(BlockError::TimestampTooOld { parent: parent.get_timestamp(), found: block.get_timestamp() });
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    if block.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimestampTooNew { now, found: block.get_timestamp() });
+    }
+
+    // merkle root check
+    let content = block.get_content();
+    let merkle_root = block::merkle_root(&content);
+    if block.get_merkle_root() != merkle_root {
+        return Err(BlockError::MerkleRootMismatch { expected: merkle_root, found: block.get_merkle_root() });
+    }
+    // odd merkle rows repeat their last hash, so repeating the last transactions of a body keeps
+    // its root and block hash
+    let mut wtxids: HashSet<H256> = HashSet::new();
+    if let Some(index) = content.iter().position(|tx| !wtxids.insert(tx.wtxid())) {
+        return Err(BlockError::DuplicateTransaction { index, hash: content[index].hash() });
+    }
+
+    // check transaction in block valid, in parallel; the coinbase has no sender to sign it, and
+    // transactions verified on their way into the mempool are not verified again
+    let cache = blockchain.signature_cache();
+    let (indices, unverified): (Vec<usize>, Vec<SignedTransaction>) = content.iter().enumerate()
+        .filter(|(_, tx)| !tx.is_coinbase() && !cache.contains(&tx.wtxid()))
+        .map(|(index, tx)| (index, tx.clone()))
+        .unzip();
+    if let Err(i) = transaction::verify_witnesses(&unverified, &blockchain.chain_id()) {
+        let index = indices[i];
+        return Err(BlockError::InvalidTransaction { index, hash: content[index].hash(), error: TransactionError::InvalidSignature });
+    }
+    for tx in unverified.iter() {
+        cache.insert(tx.wtxid());
+    }
+
+    // state transition check: the content must replay cleanly on top of the parent state, and
+    // the header must commit to the result
+    let parent_state = blockchain.get_state(parent_hash)
+        .map_err(|_| BlockError::UnknownParent(parent_hash))?;
+    let parent_height = blockchain.height(parent_hash)
+        .map_err(|_| BlockError::UnknownParent(parent_hash))?;
+    let state_root = apply_block(&parent_state, block, parent_height + 1)?.root();
+    if block.get_state_root() != state_root {
+        return Err(BlockError::StateRootMismatch { expected: state_root, found: block.get_state_root() });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::Transaction;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn genesis_key() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap()
+    }
+
+    // transaction from the key's address, signed for the chain started by genesis_key
+    fn signed(key: &Ed25519KeyPair, receiver: Address, value: u64, fee: u64, nonce: u64) -> SignedTransaction {
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let t = Transaction::new(sender, receiver, Amount::new(value), Amount::new(fee), nonce);
+        let chain_id = Blockchain::new(genesis_key()).chain_id();
+        let sig = transaction::sign(&t, &chain_id, key).as_ref().to_vec();
+        SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+    }
+
+    // mine a block on top of parent carrying the given content
+    fn mine(blockchain: &Blockchain, parent: H256, content: Vec<SignedTransaction>) -> Block {
+        let parent_block = blockchain.get_parent_block(parent).unwrap();
+        let difficulty = parent_block.get_difficulty();
+        let height = blockchain.height(parent).unwrap() + 1;
+        let state_root = apply_block_content(&blockchain.get_state(parent).unwrap(), &content, height)
+            .map(|state| state.root())
+            .unwrap_or_default();
+        let data = block::build_content(content);
+        let merkle_root = block::merkle_root(&data.get_content_data());
+        let timestamp = parent_block.get_timestamp() + 1;
+        let mut nonce = 0;
+        loop {
+            let header = block::build_header(parent, nonce, difficulty, timestamp, merkle_root, state_root);
+            let new_block = block::build_block(header, data.clone());
+            if new_block.hash() <= difficulty {
+                return new_block;
+            }
+            nonce += 1;
+        }
+    }
+
+    #[test]
+    fn accepts_valid_block() {
+        let key = genesis_key();
+        let mut blockchain = Blockchain::new(genesis_key());
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver: Address = [7u8; 20].into();
+        let (nonce, balance) = blockchain.get_state(blockchain.tip()).unwrap().get(sender).unwrap();
+        let tx = signed(&key, receiver, 10, 0, nonce + 1);
+        let new_block = mine(&blockchain, blockchain.tip(), vec![tx]);
+        assert_eq!(validate_block(&blockchain, &new_block), Ok(()));
+
+        // the post-state is derived locally on insert
+        blockchain.insert(&new_block);
+        let state = blockchain.get_state(new_block.hash()).unwrap();
+        assert_eq!(state.get(sender), Some((nonce + 1, Amount::new(balance.as_u64() - 10))));
+        assert_eq!(state.get(receiver), Some((0, Amount::new(10))));
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        let key = genesis_key();
+        let mut blockchain = Blockchain::new(genesis_key());
+        let genesis_hash = blockchain.tip();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let (nonce, balance) = blockchain.get_state(genesis_hash).unwrap().get(sender).unwrap();
+
+        // random blocks use the easiest difficulty
+        let random_block = generate_random_block(&genesis_hash);
+        match validate_block(&blockchain, &random_block) {
+            Err(BlockError::DifficultyMismatch { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // unknown parent
+        let orphan = generate_random_block(&random_block.hash());
+        assert_eq!(validate_block(&blockchain, &orphan), Err(BlockError::UnknownParent(random_block.hash())));
+
+        // nonce replayed
+        let tx = signed(&key, sender, 10, 0, nonce);
+        let replayed = mine(&blockchain, genesis_hash, vec![tx]);
+        match validate_block(&blockchain, &replayed) {
+            Err(BlockError::InvalidTransaction { index: 0, error: TransactionError::InvalidNonce { .. }, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // spending more than the balance is rejected, and insert refuses to derive a state for it
+        let tx = signed(&key, sender, balance.as_u64() + 1, 0, nonce + 1);
+        let overspend = mine(&blockchain, genesis_hash, vec![tx]);
+        match validate_block(&blockchain, &overspend) {
+            Err(BlockError::InvalidTransaction { index: 0, error: TransactionError::InsufficientBalance { .. }, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        blockchain.insert(&overspend);
+        assert!(!blockchain.is_present(overspend.hash()));
+
+        // header committing to a different state
+        let mut forged_state = blockchain.get_state(genesis_hash).unwrap();
+        forged_state.insert(sender, nonce, Amount::new(balance.as_u64() + 1));
+        let empty = mine(&blockchain, genesis_hash, vec![]);
+        let forged = loop {
+            let header = block::build_header(genesis_hash, rand::random(), empty.get_difficulty(), empty.get_timestamp(), empty.get_merkle_root(), forged_state.root());
+            let forged = block::build_block(header, block::build_content(vec![]));
+            if forged.hash() <= forged.get_difficulty() {
+                break forged;
+            }
+        };
+        match validate_block(&blockchain, &forged) {
+            Err(BlockError::StateRootMismatch { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a body repeating its last transaction under the header of the real one
+        let txs: Vec<SignedTransaction> = (1..=3).map(|i| signed(&key, sender, 1, 0, nonce + i)).collect();
+        let real = mine(&blockchain, genesis_hash, txs.clone());
+        let mut repeated = txs.clone();
+        repeated.push(txs[2].clone());
+        let mutated = block::build_block(real.get_header(), block::build_content(repeated));
+        assert_eq!(mutated.hash(), real.hash());
+        assert_eq!(validate_block(&blockchain, &mutated), Err(BlockError::DuplicateTransaction { index: 3, hash: txs[2].hash() }));
+        assert_eq!(validate_block(&blockchain, &real), Ok(()));
+
+        // already known
+        let new_block = mine(&blockchain, genesis_hash, vec![]);
+        blockchain.insert(&new_block);
+        assert_eq!(validate_block(&blockchain, &new_block), Err(BlockError::AlreadyKnown));
+    }
+
+    #[test]
+    fn apply_transfer_to_self() {
+        let key = genesis_key();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new();
+        state.insert(sender, 0, Amount::new(100));
+        let tx = signed(&key, sender, 40, 0, 1);
+        apply_transaction(&mut state, &tx).unwrap();
+        assert_eq!(state.get(sender), Some((1, Amount::new(100))));
+        assert_eq!(
+            apply_transaction(&mut state, &tx),
+            Err(TransactionError::InvalidNonce { expected: 2, found: 1 })
+        );
+    }
+
+    #[test]
+    fn fee_is_charged_on_top_of_value() {
+        let key = genesis_key();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver: Address = [1; 20].into();
+        let mut state = State::new();
+        state.insert(sender, 0, Amount::new(100));
+
+        apply_transaction(&mut state, &signed(&key, receiver, 40, 5, 1)).unwrap();
+        assert_eq!(state.get(sender), Some((1, Amount::new(55))));
+        assert_eq!(state.get(receiver), Some((0, Amount::new(40))));
+
+        assert_eq!(
+            apply_transaction(&mut state, &signed(&key, receiver, 50, 6, 2)),
+            Err(TransactionError::InsufficientBalance { balance: Amount::new(55), cost: Amount::new(56) })
+        );
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let key = genesis_key();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver: Address = [1; 20].into();
+        let mut state = State::new();
+        state.insert(sender, 0, Amount::new(100));
+        state.insert(receiver, 0, Amount::new(u64::MAX - 10));
+
+        // crediting the receiver would overflow, and nothing is applied
+        let before = state.clone();
+        assert_eq!(apply_transaction(&mut state, &signed(&key, receiver, 11, 0, 1)), Err(TransactionError::Overflow));
+        assert_eq!(state, before);
+
+        // value plus fee does not fit
+        assert_eq!(apply_transaction(&mut state, &signed(&key, receiver, u64::MAX, 1, 1)), Err(TransactionError::Overflow));
+
+        // neither does the next nonce
+        state.insert(sender, u64::MAX, Amount::new(100));
+        assert_eq!(apply_transaction(&mut state, &signed(&key, receiver, 1, 0, 0)), Err(TransactionError::Overflow));
+    }
+
+    #[test]
+    fn coinbase_collects_reward_and_fees() {
+        let key = genesis_key();
+        let mut blockchain = Blockchain::new(genesis_key());
+        let genesis_hash = blockchain.tip();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let miner: Address = [3u8; 20].into();
+        let (nonce, _) = blockchain.get_state(genesis_hash).unwrap().get(sender).unwrap();
+        let tx = signed(&key, [7u8; 20].into(), 10, 4, nonce + 1);
+
+        let reward = BLOCK_REWARD.checked_add(Amount::new(4)).unwrap();
+        let coinbase = SignedTransaction::coinbase(miner, reward, 1);
+        let new_block = mine(&blockchain, genesis_hash, vec![coinbase, tx.clone()]);
+        assert_eq!(validate_block(&blockchain, &new_block), Ok(()));
+        blockchain.insert(&new_block);
+        assert_eq!(blockchain.get_state(new_block.hash()).unwrap().get(miner), Some((0, reward)));
+
+        // the coinbase must come first
+        let coinbase = SignedTransaction::coinbase(miner, BLOCK_REWARD, 1);
+        let misplaced = mine(&blockchain, genesis_hash, vec![tx, coinbase]);
+        match validate_block(&blockchain, &misplaced) {
+            Err(BlockError::InvalidTransaction { index: 1, error: TransactionError::UnexpectedCoinbase, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_excessive_coinbase() {
+        let blockchain = Blockchain::new(genesis_key());
+        let genesis_hash = blockchain.tip();
+        let miner: Address = [3u8; 20].into();
+
+        let too_much = BLOCK_REWARD.checked_add(Amount::new(1)).unwrap();
+        let greedy = mine(&blockchain, genesis_hash, vec![SignedTransaction::coinbase(miner, too_much, 1)]);
+        assert_eq!(
+            validate_block(&blockchain, &greedy),
+            Err(BlockError::ExcessiveCoinbase { allowed: BLOCK_REWARD, found: too_much })
+        );
+
+        let wrong_height = mine(&blockchain, genesis_hash, vec![SignedTransaction::coinbase(miner, BLOCK_REWARD, 2)]);
+        assert_eq!(
+            validate_block(&blockchain, &wrong_height),
+            Err(BlockError::CoinbaseHeightMismatch { expected: 1, found: 2 })
+        );
+    }
+
+    #[test]
+    fn rejects_replays_from_other_chains() {
+        let key = genesis_key();
+        let blockchain = Blockchain::new(genesis_key());
+        let genesis_hash = blockchain.tip();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let (nonce, _) = blockchain.get_state(genesis_hash).unwrap().get(sender).unwrap();
+
+        // the same transaction signed for another network
+        let t = Transaction::new(sender, [7u8; 20].into(), Amount::new(10), Amount::ZERO, nonce + 1);
+        let sig = transaction::sign(&t, &H256::from([1; 32]), &key).as_ref().to_vec();
+        let foreign = SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec());
+        let state = blockchain.get_state(genesis_hash).unwrap();
+        assert_eq!(validate_transaction(&state, &foreign, &blockchain.chain_id()), Err(TransactionError::InvalidSignature));
+        let replayed = mine(&blockchain, genesis_hash, vec![foreign]);
+        match validate_block(&blockchain, &replayed) {
+            Err(BlockError::InvalidTransaction { index: 0, error: TransactionError::InvalidSignature, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a valid signature over someone else's sender address
+        let other: Address = [5u8; 20].into();
+        let t = Transaction::new(other, [7u8; 20].into(), Amount::new(10), Amount::ZERO, 1);
+        let sig = transaction::sign(&t, &blockchain.chain_id(), &key).as_ref().to_vec();
+        let spoofed = SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec());
+        assert_eq!(
+            validate_transaction(&state, &spoofed, &blockchain.chain_id()),
+            Err(TransactionError::SenderMismatch { expected: sender, found: other })
+        );
+    }
+
+    #[test]
+    fn multisig_account_spends_with_enough_signatures() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|i| Ed25519KeyPair::from_seed_unchecked(&[i + 1; 32]).unwrap()).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|key| key.public_key().as_ref().to_vec()).collect();
+        let treasury = Address::from_multisig(2, &public_keys);
+        let receiver: Address = [1; 20].into();
+        let mut state = State::new();
+        state.insert(treasury, 0, Amount::new(100));
+
+        let chain_id = H256::from([1; 32]);
+        let t = Transaction::new(treasury, receiver, Amount::new(40), Amount::ZERO, 1);
+        let pair = |i: usize| (public_keys[i].clone(), transaction::sign(&t, &chain_id, &keys[i]).as_ref().to_vec());
+
+        let one = SignedTransaction::new_multisig(t.clone(), 2, public_keys.clone(), vec![pair(1)]);
+        assert_eq!(validate_transaction(&state, &one, &chain_id), Err(TransactionError::InvalidSignature));
+
+        // a single key of the set is not the account
+        let alone = SignedTransaction::new(t.clone(), pair(0).1, public_keys[0].clone());
+        match validate_transaction(&state, &alone, &chain_id) {
+            Err(TransactionError::SenderMismatch { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let two = SignedTransaction::new_multisig(t.clone(), 2, public_keys.clone(), vec![pair(2), pair(0)]);
+        assert_eq!(validate_transaction(&state, &two, &chain_id), Ok(()));
+        apply_transaction(&mut state, &two).unwrap();
+        assert_eq!(state.get(treasury), Some((1, Amount::new(60))));
+    }
+
+    #[test]
+    fn block_signatures_are_cached() {
+        let key = genesis_key();
+        let blockchain = Blockchain::new(genesis_key());
+        let genesis_hash = blockchain.tip();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let (nonce, _) = blockchain.get_state(genesis_hash).unwrap().get(sender).unwrap();
+        let tx = signed(&key, [7u8; 20].into(), 10, 0, nonce + 1);
+
+        // a bad signature is reported at its index in the block
+        let forged = SignedTransaction::new(tx.get_t(), vec![0; 64], key.public_key().as_ref().to_vec());
+        let coinbase = SignedTransaction::coinbase([3; 20].into(), BLOCK_REWARD, 1);
+        let bad = mine(&blockchain, genesis_hash, vec![coinbase, forged.clone()]);
+        match validate_block(&blockchain, &bad) {
+            Err(BlockError::InvalidTransaction { index: 1, error: TransactionError::InvalidSignature, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!blockchain.signature_cache().contains(&forged.wtxid()));
+
+        let good = mine(&blockchain, genesis_hash, vec![tx.clone()]);
+        assert_eq!(validate_block(&blockchain, &good), Ok(()));
+        assert!(blockchain.signature_cache().contains(&tx.wtxid()));
+    }
+}
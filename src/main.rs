@@ -8,12 +8,18 @@ pub mod types;
 pub mod miner;
 pub mod network;
 pub mod txgen;
+pub mod wallet;
 
 use blockchain::{Blockchain, Mempool};
+use blockchain::store::FileStore;
+use blockchain::difficulty::RetargetParams;
+use types::address::Address;
+use wallet::Wallet;
 use clap::clap_app;
 use smol::channel;
 use log::{error, info};
 use api::Server as ApiServer;
+use api::WalletApi;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
@@ -28,15 +34,41 @@ fn main() {
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server; its wallet endpoints are enabled by BITCOIN_API_TOKEN and require it as a bearer token")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg verify_threads: --("verify-threads") [INT] default_value("0") "Sets the number of threads checking the signatures of a block, 0 for one per CPU")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outbound peers to keep connected, picked from addresses learned from peers")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] default_value("8388608") "Sets the largest message accepted from a peer; a peer sending a larger one is disconnected")
+     (@arg peer_queue: --("peer-queue") [INT] default_value("1024") "Sets the number of messages queued for each peer before it counts as slow")
+     (@arg slow_peers: --("slow-peers") [POLICY] default_value("disconnect") "Sets what happens to messages for a slow peer: drop them, or disconnect the peer")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where blocks are persisted across restarts")
+     (@arg block_time: --("block-time") [MS] default_value("10000") "Sets the average block interval in milliseconds that difficulty retargeting aims for (must match all peers)")
+     (@arg retarget_interval: --("retarget-interval") [INT] default_value("16") "Sets the number of blocks between difficulty retargets (must match all peers)")
+     (@arg miner_address: --("miner-address") [ADDR] "Sets the address that receives the block reward and fees of mined blocks")
+     (@arg wallet: --wallet [FILE] "Sets the encrypted keystore holding the wallet's keys, created if missing; the passphrase is read from BITCOIN_WALLET_PASSPHRASE and a new wallet is restored from BITCOIN_WALLET_MNEMONIC if set")
+     (@arg show_mnemonic: --("show-mnemonic") "Prints the wallet's recovery phrase to stdout at start; anyone who reads it controls every derived account")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
+
+    // size the pool that checks block signatures once, for the whole run
+    let verify_threads = matches
+        .value_of("verify_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing verify threads: {}", e);
+            process::exit(1);
+        });
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(verify_threads)
+        .thread_name(|i| format!("verify-{}", i))
+        .build_global()
+        .unwrap();
     
     // parse p2p server address
     let p2p_addr = matches
@@ -50,7 +82,6 @@ fn main() {
 
     // generate keys for each node
     let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap(); // seed is 0 for 6000
-    let key_pair_clone = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
 
     // if p2p_addr == "127.0.0.1:6001".parse::<net::SocketAddr>().unwrap() {
     //     key_pair = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap(); // seed is 1 for 6001
@@ -61,9 +92,101 @@ fn main() {
     //     key_pair_clone = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
     // }
 
-    let blockchain = Arc::new(Mutex::new(Blockchain::new(key_pair)));
+    // restore the blockchain from disk if a data directory is given
+    let mut blockchain = match matches.value_of("data_dir") {
+        Some(dir) => {
+            let store = FileStore::open(dir).unwrap_or_else(|e| {
+                error!("Error opening block store in {}: {}", dir, e);
+                process::exit(1);
+            });
+            Blockchain::with_store(key_pair, Box::new(store)).unwrap_or_else(|e| {
+                error!("Error loading blocks from {}: {}", dir, e);
+                process::exit(1);
+            })
+        }
+        None => Blockchain::new(key_pair),
+    };
+    // parse difficulty retargeting parameters
+    let target_block_time = matches
+        .value_of("block_time")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block time: {}", e);
+            process::exit(1);
+        });
+    let retarget_interval = matches
+        .value_of("retarget_interval")
+        .unwrap()
+        .parse::<u128>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing retarget interval: {}", e);
+            process::exit(1);
+        });
+    let retarget = RetargetParams {interval: retarget_interval, target_block_time};
+    if let Err(e) = retarget.validate() {
+        error!("Error in difficulty retargeting parameters: {}", e);
+        process::exit(1);
+    }
+    blockchain.set_retarget_params(retarget);
+    let blockchain = Arc::new(Mutex::new(blockchain));
     let mempool = Arc::new(Mutex::new(Mempool::new()));
 
+    // open the wallet, keeping keys in memory only if no keystore is given; a new wallet is
+    // restored from BITCOIN_WALLET_MNEMONIC if set
+    let mnemonic = std::env::var("BITCOIN_WALLET_MNEMONIC").ok();
+    let mut wallet = match matches.value_of("wallet") {
+        Some(path) => {
+            let passphrase = std::env::var("BITCOIN_WALLET_PASSPHRASE").unwrap_or_else(|_| {
+                error!("BITCOIN_WALLET_PASSPHRASE must be set to open the wallet");
+                process::exit(1);
+            });
+            let path = std::path::Path::new(path);
+            let opened = if path.exists() {
+                Wallet::open(path, &passphrase)
+            } else if let Some(phrase) = &mnemonic {
+                Wallet::restore(path, &passphrase, phrase)
+            } else {
+                Wallet::create(path, &passphrase).inspect(|_| {
+                    info!("Created wallet {}; run once with --show-mnemonic to write down its recovery phrase", path.display());
+                })
+            };
+            opened.unwrap_or_else(|e| {
+                error!("Error opening wallet {}: {}", path.display(), e);
+                process::exit(1);
+            })
+        }
+        None => match &mnemonic {
+            Some(phrase) => Wallet::from_mnemonic(phrase).unwrap_or_else(|e| {
+                error!("Error restoring wallet: {}", e);
+                process::exit(1);
+            }),
+            None => Wallet::in_memory(),
+        },
+    };
+    // the phrase gives away every derived key, so it never reaches the logs unasked
+    if matches.is_present("show_mnemonic") {
+        println!("Recovery phrase of the wallet: {}", wallet.mnemonic());
+    }
+    // the genesis coins are held by the seed 0 key
+    wallet.import_seed(&[0; 32]).unwrap_or_else(|e| {
+        error!("Error importing the genesis key: {}", e);
+        process::exit(1);
+    });
+    // bring back derived accounts used on the restored chain
+    let tip_state = {
+        let blockchain = blockchain.lock().unwrap();
+        blockchain.get_state(blockchain.tip())
+    };
+    if let Ok(state) = tip_state {
+        match wallet.discover(&state) {
+            Ok(0) => {}
+            Ok(found) => info!("Discovered {} wallet accounts", found),
+            Err(e) => error!("Error discovering wallet accounts: {}", e),
+        }
+    }
+    let wallet = Arc::new(Mutex::new(wallet));
+
     // parse api server address
     let api_addr = matches
         .value_of("api_addr")
@@ -77,8 +200,52 @@ fn main() {
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
-    // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    // start the p2p server, keeping learned peer addresses next to the blocks
+    let target_outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+    let max_frame_size = matches
+        .value_of("max_frame_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing maximum frame size: {}", e);
+            process::exit(1);
+        });
+    let write_queue_size = matches
+        .value_of("peer_queue")
+        .unwrap()
+        .parse::<usize>()
+        .ok()
+        .filter(|size| *size > 0)
+        .unwrap_or_else(|| {
+            error!("Error parsing peer queue size: expected a positive number");
+            process::exit(1);
+        });
+    let slow_peer_policy = matches
+        .value_of("slow_peers")
+        .unwrap()
+        .parse::<network::peer::SlowPeerPolicy>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing slow peer policy: {}", e);
+            process::exit(1);
+        });
+    let server_config = network::server::Config {
+        target_outbound,
+        address_book: matches.value_of("data_dir").map(|dir| std::path::Path::new(dir).join("peers.json")),
+        max_frame_size,
+        write_queue_size,
+        slow_peer_policy,
+    };
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, &blockchain, server_config).unwrap_or_else(|e| {
+        error!("Error starting P2P server: {}", e);
+        process::exit(1);
+    });
     server_ctx.start().unwrap();
 
     // start the worker
@@ -100,16 +267,29 @@ fn main() {
     worker_ctx.start();
 
     // start the transaction generator
-    let (tx_ctx, tx, finished_block_chan_tx) = txgen::new(mempool.clone(), key_pair_clone, blockchain.clone());
+    let (tx_ctx, tx, finished_block_chan_tx) = txgen::new(mempool.clone(), wallet.clone(), blockchain.clone());
     let tx_worker_ctx = txgen::worker::Worker::new(&server, finished_block_chan_tx, mempool.clone());
     tx_ctx.start();
     tx_worker_ctx.start();
     
+    // parse the address credited with the reward of mined blocks
+    let miner_address = matches.value_of("miner_address").map(|address| {
+        address.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing miner address: {}", e);
+            process::exit(1);
+        })
+    });
+
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(blockchain.clone(), mempool.clone());
+    let (miner_ctx, miner, finished_block_chan) = miner::new(blockchain.clone(), mempool.clone(), miner_address);
     let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, blockchain.clone());
     miner_ctx.start();
     miner_worker_ctx.start();
+
+    // keep the mempool, miner and transaction generator in line with the canonical chain
+    let chain_events = blockchain.lock().unwrap().subscribe();
+    let reorg_worker = blockchain::reorg::Worker::new(chain_events, &blockchain, &mempool, &miner, &tx);
+    reorg_worker.start();
     
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
@@ -127,9 +307,14 @@ fn main() {
                     };
                     match server.connect(addr) {
                         Ok(_) => {
+                            // the server asks the peer for headers and addresses on connect
                             info!("Connected to outgoing peer {}", &addr);
                             break;
                         }
+                        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                            error!("Not connecting to peer {}: {}", addr, e);
+                            break;
+                        }
                         Err(e) => {
                             error!(
                                 "Error connecting to peer {}, retrying in one second: {}",
@@ -146,11 +331,21 @@ fn main() {
 
 
     // start the API server
+    // the wallet endpoints spend the node's funds, so they are only served with a token
+    let wallet_api = match std::env::var("BITCOIN_API_TOKEN") {
+        Ok(token) if !token.is_empty() => Some(WalletApi { wallet: wallet.clone(), token }),
+        _ => {
+            info!("Wallet API disabled; set BITCOIN_API_TOKEN to enable it");
+            None
+        }
+    };
     ApiServer::start(
         api_addr,
         &miner,
         &server,
         &blockchain,
+        &mempool,
+        wallet_api,
         &tx
     );
 
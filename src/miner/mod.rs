@@ -1,22 +1,29 @@
 pub mod worker;
 
-use log::info;
+use log::{info, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 
 use std::thread;
 use std::sync::{Arc, Mutex};
 
 use crate::types::block::{self, Block, Content, State};
 use crate::blockchain::{Blockchain, Mempool};
-use crate::types::merkle::MerkleTree;
-use super::types::hash::{Hashable, H256};
-use super::types::transaction;
+use crate::blockchain::{difficulty, validation};
+use crate::blockchain::error::BlockchainError;
 use crate::types::address::Address;
+use crate::types::transaction::SignedTransaction;
+use super::types::hash::{Hashable, H256};
 
 use ring::signature::Ed25519KeyPair;
 
+// maximum number of transactions in a mined block
+const BLOCK_TX_LIMIT: usize = 5;
+
+// how long a block template is mined on before picking up new mempool transactions
+const TEMPLATE_REFRESH: Duration = Duration::from_millis(500);
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
@@ -29,6 +36,17 @@ enum OperatingState {
     ShutDown,
 }
 
+// block being mined on top of a parent, everything except the header nonce and timestamp
+struct Template {
+    parent: H256,
+    parent_timestamp: u128,
+    difficulty: H256,
+    data: Content,
+    merkle_root: H256,
+    state_root: H256,
+    created: Instant,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -36,6 +54,7 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    reward_address: Option<Address>, // receiver of the block reward and fees, if any
 }
 
 #[derive(Clone)]
@@ -44,7 +63,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: Arc<Mutex<Mempool>>, reward_address: Option<Address>) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
 
@@ -57,6 +76,7 @@ pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: Arc<Mutex<Mempool>>) ->
         finished_block_chan: finished_block_sender,
         blockchain: clone_context,
         mempool: clone_mempool,
+        reward_address,
     };
 
     let handle = Handle {
@@ -72,7 +92,7 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
     let blockchain = Arc::new(Mutex::new(Blockchain::new(key_pair)));
     let mempool = Arc::new(Mutex::new(Mempool::new()));
-    new(blockchain, mempool)
+    new(blockchain, mempool, Some([0; 20].into()))
 }
 
 impl Handle {
@@ -106,6 +126,7 @@ impl Context {
 
         // MY CODE
         let mut parent = {self.blockchain.lock().unwrap().tip()}; // MY CODE
+        let mut template: Option<Template> = None;
         // END OF MY CODE
         // let mut current_mempool = self.mempool.lock().unwrap().get_mempool();
 
@@ -123,6 +144,7 @@ impl Context {
                         ControlSignal::Start(i) => {
                             info!("Miner starting in continuous mode with lambda {}", i);
                             self.operating_state = OperatingState::Run(i);
+                            parent = self.blockchain.lock().unwrap().tip();
                         }
                         ControlSignal::Update => {
                             // in paused state, don't need to update
@@ -145,7 +167,8 @@ impl Context {
                                 self.operating_state = OperatingState::Run(i);
                             }
                             ControlSignal::Update => {
-                                unimplemented!()
+                                // the tip changed, mine on top of the new one
+                                parent = self.blockchain.lock().unwrap().tip();
                             }
                         };
                     }
@@ -161,213 +184,61 @@ impl Context {
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
             
             // BEGINNING OF MY CODE
-            
-            // build a block
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(); // time now in milliseconds
 
-            let blockchain = {self.blockchain.lock().unwrap()};
-            let parent_block = blockchain.get_parent_block(parent);
-            let difficulty = parent_block.get_difficulty();
-            
-            // content is empty for now
-            let content_data: Vec<transaction::SignedTransaction> = Vec::new();
-            let data: Content = block::build_content(content_data);
+            // refresh the block template when the parent changes or it gets stale
+            let stale = match &template {
+                Some(t) => t.parent != parent || t.created.elapsed() >= TEMPLATE_REFRESH,
+                None => true,
+            };
+            if stale {
+                match self.build_template(parent) {
+                    Ok(t) => template = Some(t),
+                    Err(e) => {
+                        // the parent went away under us, start over from the current tip
+                        warn!("Cannot build block template: {}", e);
+                        parent = self.blockchain.lock().unwrap().tip();
+                        template = None;
+                        continue;
+                    }
+                }
+            }
+            let current = template.as_ref().unwrap();
 
-            // get merkle root from content data
-            let merkle_tree = MerkleTree::new(&data.get_content_data());
-            let merkle_root = merkle_tree.root();
+            // build a block
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(); // time now in milliseconds
+            let timestamp = std::cmp::max(now, current.parent_timestamp + 1);
 
             // nonce, randomly generated
             let nonce = rand::random();
 
-            // state
-            let state = State::new();
-
             // construct block
-            let header = block::build_header(parent, nonce, difficulty, timestamp, merkle_root);
-            let mut new_block = block::build_block(header, data, state);
-
-            // add transactions to block being mined
-            // set limit to 50, CAN CHANGE LATER
-            let mut count = 0;
-
-            // println!("inside miner loop");
-            let current_mempool = {self.mempool.lock().unwrap()};
+            let header = block::build_header(parent, nonce, current.difficulty, timestamp, current.merkle_root, current.state_root);
+            let new_block = block::build_block(header, current.data.clone());
 
             // check if successful
-            if new_block.hash() <= difficulty {
-                // println!("mempool empty?");
-                // println!("{}", current_mempool.get_mempool().is_empty());
-
-                for (_k, v) in current_mempool.get_mempool().iter() {
-                    if count < 5 { // LIMIT
-                        new_block.insert_transaction(v.clone());
-                        // println!("{}", v.hash());
-                        // println!("^^hash");
-                        count = count + 1;
-                        // println!("{}", count);
-                        // println!("^^count");
-
-                        // remove from mempool
-                        // {self.mempool.lock().unwrap().remove(k.clone())};
-                    }
-                    else {
-                        break;
-                    }
-                }
-
-                drop(current_mempool);
-
-                let transactions_in_block = new_block.get_content();
-                for el in transactions_in_block {
-                    {self.mempool.lock().unwrap().remove(el.hash())};
-                    // println!("removed from mempool");
-                }
-
-                // println!("value of count");
-                // println!("{}", count);
-
-                // println!("mempool empty after inserting into block?");
-                // println!("{}", {self.mempool.lock().unwrap().get_mempool().is_empty()});
-
-                // update state in block
-                let content = new_block.get_content(); // parent block above when generating new block
-                let mut parent_state = parent_block.get_state();
-
-                // loop through transactions in content
-                for tx in content {
-                    // println!("inside tx content loop");
-
-                    let public_key = tx.get_public_key();
-                    let sender = Address::from_public_key_bytes(public_key.as_slice());
-
-                    // update state
-                    // implement checks
-                    let transaction_verified = transaction::verify(&tx.get_t(), &tx.get_public_key(), &tx.get_sig());
-                    // println!("{}", transaction_verified);
-                    if transaction_verified {
-                        // spending check
-                        // println!("transaction verified");
-                        if parent_state.contains_key(sender) {
-                            let sender_nonce = parent_state.get(sender).0;
-                            let sender_balance = parent_state.get(sender).1;
-
-                            let receiver = tx.get_t().get_receiver();
-                            let receiver_nonce: u32;
-                            let receiver_balance: u32;
-
-                            // if receiver is new, make a new account
-                            if parent_state.contains_key(receiver) {
-                                receiver_nonce = parent_state.get(receiver).0;
-                                receiver_balance = parent_state.get(receiver).1;
-                            }
-                            else {
-                                receiver_nonce = rand::random();
-                                receiver_balance = 0;
-                            }
-
-                            // println!("parent state contains key");
-                            if sender_balance >= tx.get_t().get_value() {
-                                // println!("balance correct");
-                                if tx.get_t().get_nonce() == (1 + sender_nonce) {
-                                    // println!("nonce correct");
-                                    parent_state.insert(sender, sender_nonce, sender_balance - tx.get_t().get_value()); // update sender account
-                                    parent_state.insert(receiver, receiver_nonce, receiver_balance + tx.get_t().get_value()); // update receiver account
-                                }
-                            }
+            if new_block.hash() <= current.difficulty {
+                let mut blockchain = self.blockchain.lock().unwrap();
+                match validation::validate_block(&blockchain, &new_block) {
+                    Ok(()) => {
+                        blockchain.insert(&new_block);
+                        parent = blockchain.tip();
+                        let state = blockchain.get_state(new_block.hash());
+                        drop(blockchain);
+
+                        // update transaction mempool
+                        match state {
+                            Ok(state) => self.clean_mempool(&new_block, &state),
+                            Err(e) => warn!("Not cleaning mempool: {}", e),
                         }
-                    }
-                }
 
-                // update state of block
-                new_block.put_state(parent_state.clone());
-                // println!("state empty?");
-                // println!("{}", parent_state.get_state().is_empty());
-
-                // update transaction mempool
-                let mempool_copy = {self.mempool.lock().unwrap()};
-                // println!("is mempool_copy empty?");
-                // println!("{}", mempool_copy.get_mempool().is_empty());
-
-                let mut to_remove = Mempool::new();
-                // let mut new_mempool_another = Mempool::new();
-                // Arc::new(Mutex::new(Mempool::new()))
-
-                for (_k, v) in mempool_copy.get_mempool().iter() {
-                    let current_state = new_block.get_state();
-
-                    let public_key = v.get_public_key();
-                    let sender = Address::from_public_key_bytes(public_key.as_slice());
-                    // println!("state contains sender?");
-                    // println!("{}", current_state.contains_key(sender));
-                    if current_state.contains_key(sender) {
-                        let sender_balance = current_state.get(sender).1;
-                        let sender_nonce = current_state.get(sender).0;
-    
-                        // implement checks
-                        let transaction_verified = transaction::verify(&v.get_t(), &v.get_public_key(), &v.get_sig());
-                        // println!("transaction verified");
-                        if transaction_verified {
-                            // spending check
-                            if new_block.get_state().contains_key(sender) {
-                                // println!("contains sender");
-                                if sender_balance >= v.get_t().get_value() {
-                                    // println!("sender balance");
-                                    // println!("{}", sender_balance);
-                                    // println!("transaction value");
-                                    // println!("{}", v.get_t().get_value());
-                                    if !(v.get_t().get_nonce() == (1 + sender_nonce)) {
-                                        // println!("nonce correct");
-                                        // new_mempool.insert(v.hash(), &v);
-                                        // new_mempool_another.insert(v.hash(), &v);
-                                        to_remove.insert(v.hash(), &v);
-                                    }
-                                    
-                                }
-                                else {
-                                    to_remove.insert(v.hash(), &v);
-                                }
-                            }
-                            else {
-                                to_remove.insert(v.hash(), &v);
-                            }
-                        }
-                        else {
-                            to_remove.insert(v.hash(), &v);
-                        }
+                        // send off finished block
+                        self.finished_block_chan.send(new_block.clone()).expect("Send finished block error");
+                    }
+                    Err(e) => {
+                        warn!("Discarding mined block {}: {}", new_block.hash(), e);
+                        parent = blockchain.tip();
+                        template = None;
                     }
-                }
-
-                drop(mempool_copy);
-
-                // println!("new mempool empty?");
-                // println!("{}", new_mempool.get_mempool().is_empty());
-
-                for (el, _v) in to_remove.get_mempool().iter() {
-                    self.mempool.lock().unwrap().remove(*el);
-                }
-
-                // self.mempool = Arc::new(Mutex::new(new_mempool));
-                
-
-                // send off finished block
-
-                // println!("before send off miner mod");
-                self.finished_block_chan.send(new_block.clone()).expect("Send finished block error");
-                // println!("after send off miner mod");
-
-                drop(blockchain);
-                {self.blockchain.lock().unwrap().insert(&new_block)};
-                // println!("after insert new block");
-
-                parent = self.blockchain.lock().unwrap().tip();
-                // println!("after assign parent");
-                let zero_parent = H256::from([0; 32]);
-
-                // current_mempool = new_mempool_another.get_mempool();
-
-                if parent == zero_parent {
-                    break;
                 }
             }
 
@@ -381,6 +252,66 @@ impl Context {
             }
         }
     }
+
+    // select transactions from the mempool that apply cleanly on top of parent, and compute the
+    // resulting content, merkle root and state root
+    fn build_template(&self, parent: H256) -> Result<Template, BlockchainError> {
+        let (parent_block, parent_state, difficulty, height) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (
+                blockchain.get_parent_block(parent)?,
+                blockchain.get_state(parent)?,
+                difficulty::next_difficulty(&blockchain, parent)?,
+                blockchain.height(parent)? + 1,
+            )
+        };
+
+        let mut selected = self.mempool.lock().unwrap().select_for_block(&parent_state, BLOCK_TX_LIMIT);
+        if validation::apply_transactions(&parent_state, &selected).is_err() {
+            warn!("Mempool selection does not apply, mining an empty block");
+            selected.clear();
+        }
+
+        // pay the block reward and the fees to ourselves, first in the block
+        if let Some(receiver) = self.reward_address {
+            let reward = selected.iter()
+                .try_fold(validation::BLOCK_REWARD, |reward, tx| reward.checked_add(tx.get_t().get_fee()));
+            match reward {
+                Some(reward) => selected.insert(0, SignedTransaction::coinbase(receiver, reward, height as u64)),
+                None => warn!("Block reward overflows, mining without a coinbase"),
+            }
+        }
+        let (content_data, state) = match validation::apply_block_content(&parent_state, &selected, height) {
+            Ok(state) => (selected, state),
+            Err(e) => {
+                warn!("Block content does not apply, mining an empty block: {}", e);
+                (Vec::new(), parent_state)
+            }
+        };
+
+        // get merkle root from content data
+        let merkle_root = block::merkle_root(&content_data);
+        let data: Content = block::build_content(content_data);
+
+        Ok(Template {
+            parent,
+            parent_timestamp: parent_block.get_timestamp(),
+            difficulty,
+            data,
+            merkle_root,
+            state_root: state.root(),
+            created: Instant::now(),
+        })
+    }
+
+    // remove transactions included in the block, and those the new state makes permanently invalid
+    fn clean_mempool(&self, new_block: &Block, state: &State) {
+        let mut mempool = self.mempool.lock().unwrap();
+        for el in new_block.get_content() {
+            mempool.remove(el.hash());
+        }
+        mempool.prune(state);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -44,10 +44,11 @@ impl Worker {
         loop {
             let mut _block = self.finished_block_chan.recv().expect("Receive finished block error");
             
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            {self.blockchain.lock().unwrap().insert(&_block)};
-            // println!("inserted block");
-            // println!("{}", _block.hash());
+            // the miner validates and inserts the block before sending it here, so that it can
+            // keep mining on top of it right away
+            if !{self.blockchain.lock().unwrap().is_present(_block.hash())} {
+                continue;
+            }
 
             // if successful, broadcast message NewBlockHashes
             let mut message: Vec<H256> = Vec::new();
@@ -0,0 +1,228 @@
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses kept that were heard of but never connected to
+pub const MAX_NEW: usize = 1024;
+/// Most addresses kept that a connection succeeded to
+pub const MAX_TRIED: usize = 256;
+/// Failed connections after which a never connected address is forgotten
+pub const MAX_ATTEMPTS: u32 = 3;
+/// Seconds before an address is tried again after an attempt
+pub const RETRY_DELAY: u64 = 60;
+/// Most addresses sent in, or taken from, one `Addr` message
+pub const MAX_ADDR_MESSAGE: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    addr: SocketAddr,
+    last_seen: u64, // unix seconds of the last announcement or connection
+    last_attempt: u64, // unix seconds, 0 if never attempted
+    attempts: u32, // failed attempts since the last success
+}
+
+// on-disk form of the address book
+#[derive(Serialize, Deserialize, Default)]
+struct Buckets {
+    new: Vec<Entry>,
+    tried: Vec<Entry>,
+}
+
+/// Addresses of peers this node may connect to, learned from `Addr` gossip and handshakes.
+///
+/// Addresses start in the new bucket. A successful outbound connection moves an address to the
+/// tried bucket, and repeated failures drop it from the new bucket. Both buckets are bounded and
+/// drop the address seen longest ago when full. With a path, the book is kept as JSON across
+/// restarts.
+pub struct AddressBook {
+    new: HashMap<SocketAddr, Entry>,
+    tried: HashMap<SocketAddr, Entry>,
+    path: Option<PathBuf>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressBook {
+    /// Create an address book that is not persisted
+    pub fn new() -> Self {
+        AddressBook { new: HashMap::new(), tried: HashMap::new(), path: None }
+    }
+
+    /// Open the address book stored at `path`, starting empty if there is none
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let buckets: Buckets = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Buckets::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(AddressBook {
+            new: buckets.new.into_iter().map(|entry| (entry.addr, entry)).collect(),
+            tried: buckets.tried.into_iter().map(|entry| (entry.addr, entry)).collect(),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Write the book to its path, if it has one
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let buckets = Buckets {
+            new: self.new.values().cloned().collect(),
+            tried: self.tried.values().cloned().collect(),
+        };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec(&buckets)?)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Remember addresses announced by a peer
+    pub fn add(&mut self, addrs: &[SocketAddr], now: u64) {
+        for addr in addrs {
+            if let Some(entry) = self.tried.get_mut(addr) {
+                entry.last_seen = entry.last_seen.max(now);
+                continue;
+            }
+            let entry = self.new.entry(*addr).or_insert(Entry { addr: *addr, last_seen: now, last_attempt: 0, attempts: 0 });
+            entry.last_seen = entry.last_seen.max(now);
+        }
+        evict(&mut self.new, MAX_NEW);
+    }
+
+    /// Record a connection attempt, so the address is not tried again right away
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u64) {
+        let (tried, new) = (&mut self.tried, &mut self.new);
+        if let Some(entry) = tried.get_mut(addr).or_else(|| new.get_mut(addr)) {
+            entry.last_attempt = now;
+        }
+    }
+
+    /// Record a successful connection, moving the address to the tried bucket
+    pub fn good(&mut self, addr: &SocketAddr, now: u64) {
+        let mut entry = self.new.remove(addr)
+            .or_else(|| self.tried.remove(addr))
+            .unwrap_or(Entry { addr: *addr, last_seen: now, last_attempt: now, attempts: 0 });
+        entry.last_seen = now;
+        entry.attempts = 0;
+        self.tried.insert(*addr, entry);
+        evict(&mut self.tried, MAX_TRIED);
+    }
+
+    /// Record a failed connection; a never connected address is dropped after `MAX_ATTEMPTS`
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.tried.get_mut(addr) {
+            entry.attempts = entry.attempts.saturating_add(1);
+        } else if let Some(entry) = self.new.get_mut(addr) {
+            entry.attempts += 1;
+            if entry.attempts >= MAX_ATTEMPTS {
+                self.new.remove(addr);
+            }
+        }
+    }
+
+    /// Pick an address to connect to that is not excluded and was not attempted recently, from
+    /// either bucket with equal chance so that fresh addresses still get tried
+    pub fn select<F: Fn(&SocketAddr) -> bool>(&self, excluded: F, now: u64) -> Option<SocketAddr> {
+        let candidates = |bucket: &HashMap<SocketAddr, Entry>| {
+            bucket.values()
+                .filter(|entry| !excluded(&entry.addr) && now >= entry.last_attempt.saturating_add(RETRY_DELAY))
+                .map(|entry| entry.addr)
+                .choose(&mut rand::thread_rng())
+        };
+        let (first, second) = if rand::thread_rng().gen_bool(0.5) { (&self.tried, &self.new) } else { (&self.new, &self.tried) };
+        candidates(first).or_else(|| candidates(second))
+    }
+
+    /// Up to `MAX_ADDR_MESSAGE` random known addresses, to answer `GetAddr`
+    pub fn sample(&self) -> Vec<SocketAddr> {
+        self.tried.keys().chain(self.new.keys()).copied().choose_multiple(&mut rand::thread_rng(), MAX_ADDR_MESSAGE)
+    }
+
+    pub fn len(&self) -> usize {
+        self.new.len() + self.tried.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// drop the addresses seen longest ago until the bucket fits
+fn evict(bucket: &mut HashMap<SocketAddr, Entry>, max: usize) {
+    while bucket.len() > max {
+        let oldest = bucket.values().min_by_key(|entry| entry.last_seen).map(|entry| entry.addr);
+        match oldest {
+            Some(addr) => bucket.remove(&addr),
+            None => break,
+        };
+    }
+}
+
+/// Current unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn addresses_move_between_buckets() {
+        let mut book = AddressBook::new();
+        book.add(&[addr(1), addr(2)], 100);
+        assert_eq!(book.len(), 2);
+
+        // a good address survives failures, a new one is forgotten
+        book.good(&addr(1), 100);
+        for _ in 0..MAX_ATTEMPTS {
+            book.failed(&addr(1));
+            book.failed(&addr(2));
+        }
+        assert_eq!(book.sample(), vec![addr(1)]);
+
+        // recently attempted and excluded addresses are not selected
+        book.attempt(&addr(1), 100);
+        assert_eq!(book.select(|_| false, 100 + RETRY_DELAY - 1), None);
+        assert_eq!(book.select(|_| false, 100 + RETRY_DELAY), Some(addr(1)));
+        assert_eq!(book.select(|a| *a == addr(1), 100 + RETRY_DELAY), None);
+    }
+
+    #[test]
+    fn buckets_are_bounded_and_persisted() {
+        let path = std::env::temp_dir().join(format!("address-book-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut book = AddressBook::open(&path).unwrap();
+        for port in 0..(MAX_NEW as u16 + 10) {
+            book.add(&[addr(port)], port as u64);
+        }
+        book.good(&addr(5000), 1);
+        assert_eq!(book.len(), MAX_NEW + 1);
+        // the addresses seen longest ago were dropped
+        assert!(!book.new.contains_key(&addr(9)));
+        assert!(book.new.contains_key(&addr(10)));
+        book.save().unwrap();
+
+        let reopened = AddressBook::open(&path).unwrap();
+        assert_eq!(reopened.len(), MAX_NEW + 1);
+        assert_eq!(reopened.tried.keys().copied().collect::<Vec<_>>(), vec![addr(5000)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
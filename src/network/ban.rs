@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Ban score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds a misbehaving peer stays banned
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+/// Most block hashes a peer may ask for in one `GetBlocks`
+pub const MAX_GET_BLOCKS: usize = 128;
+
+/// Rule violations of a peer, each adding to its ban score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A message that does not decode, which a peer on a newer protocol may send by mistake
+    MalformedMessage,
+    /// A block that breaks a consensus rule
+    InvalidBlock,
+    /// Headers that break a consensus rule
+    InvalidHeaders,
+    /// A transaction whose witness does not verify
+    InvalidSignature,
+    /// A `GetBlocks` asking for more than `MAX_GET_BLOCKS` blocks
+    OversizedRequest,
+    /// A `Version` or `VerAck` after the handshake
+    UnexpectedHandshake,
+    /// A frame longer than the maximum frame size, which a peer started with a larger
+    /// `--max-frame-size` may send
+    OversizedFrame,
+    /// A block or header whose difficulty target is not the expected one, which a peer started
+    /// with other retarget parameters sends for valid blocks
+    UnexpectedDifficulty,
+}
+
+impl Misbehavior {
+    /// How much the violation adds to the ban score
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidHeaders => 20,
+            Misbehavior::InvalidSignature => 10,
+            Misbehavior::OversizedRequest => 20,
+            Misbehavior::UnexpectedHandshake => 10,
+            Misbehavior::OversizedFrame => 50,
+            Misbehavior::UnexpectedDifficulty => 20,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehavior::MalformedMessage => write!(f, "malformed message"),
+            Misbehavior::InvalidBlock => write!(f, "invalid block"),
+            Misbehavior::InvalidHeaders => write!(f, "invalid headers"),
+            Misbehavior::InvalidSignature => write!(f, "transaction with an invalid signature"),
+            Misbehavior::OversizedRequest => write!(f, "oversized block request"),
+            Misbehavior::UnexpectedHandshake => write!(f, "handshake message after the handshake"),
+            Misbehavior::OversizedFrame => write!(f, "oversized frame"),
+            Misbehavior::UnexpectedDifficulty => write!(f, "unexpected difficulty target"),
+        }
+    }
+}
+
+/// IP addresses banned until a given time.
+///
+/// A ban covers every port of the address, so a banned peer cannot come back from another port.
+/// Expired bans are ignored and dropped on the next change.
+#[derive(Default)]
+pub struct BanList {
+    banned: HashMap<IpAddr, u64>, // value = unix seconds the ban ends
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList { banned: HashMap::new() }
+    }
+
+    /// Ban an address for `BAN_DURATION` from `now`, extending an existing ban
+    pub fn ban(&mut self, ip: IpAddr, now: u64) {
+        self.banned.retain(|_, until| *until > now);
+        let until = self.banned.entry(ip).or_insert(0);
+        *until = (*until).max(now.saturating_add(BAN_DURATION));
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: u64) -> bool {
+        self.banned.get(ip).is_some_and(|until| *until > now)
+    }
+
+    /// Lift the ban of an address, returning whether it was banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.banned.remove(ip).is_some()
+    }
+
+    /// Lift all bans
+    pub fn clear(&mut self) {
+        self.banned.clear();
+    }
+
+    /// Banned addresses with the unix time their ban ends, earliest first
+    pub fn list(&self, now: u64) -> Vec<(IpAddr, u64)> {
+        let mut banned: Vec<(IpAddr, u64)> = self.banned.iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (*ip, *until))
+            .collect();
+        banned.sort_by_key(|(ip, until)| (*until, *ip));
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire_and_can_be_lifted() {
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut bans = BanList::new();
+        bans.ban(first, 100);
+        bans.ban(second, 200);
+        assert!(bans.is_banned(&first, 100 + BAN_DURATION - 1));
+        assert!(!bans.is_banned(&first, 100 + BAN_DURATION));
+        assert_eq!(bans.list(150), vec![(first, 100 + BAN_DURATION), (second, 200 + BAN_DURATION)]);
+        assert_eq!(bans.list(100 + BAN_DURATION), vec![(second, 200 + BAN_DURATION)]);
+
+        assert!(bans.unban(&second));
+        assert!(!bans.unban(&second));
+        assert!(!bans.is_banned(&second, 200));
+    }
+}
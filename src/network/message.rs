@@ -1,6 +1,14 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+use std::net::SocketAddr;
+
+/// Version of the peer protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version of a peer this node still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Service bit of a node that stores and serves the full chain
+pub const SERVICE_FULL_NODE: u64 = 1;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +20,23 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Ask for the headers following the first locator hash on the receiver's longest chain, up
+    /// to `stop` (all zeros for no limit)
+    GetHeaders { locator: Vec<H256>, stop: H256 },
+    Headers(Vec<Header>),
+    /// First message on a connection, from both sides. Nothing else is exchanged until each side
+    /// has acknowledged the other's version with `VerAck`. Peers on another genesis block, which
+    /// includes peers hashing with another consensus encoding, are disconnected.
+    Version {
+        protocol_version: u32,
+        genesis_hash: H256,
+        best_height: u128,
+        listen_addr: SocketAddr, // where the sender accepts connections
+        services: u64,
+    },
+    VerAck,
+    /// Ask for addresses of other peers
+    GetAddr,
+    /// Addresses of peers that accept connections
+    Addr(Vec<SocketAddr>),
 }
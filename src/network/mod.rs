@@ -1,4 +1,8 @@
+pub mod address_book;
+pub mod ban;
 pub mod message;
+pub mod orphan;
 pub mod peer;
 pub mod server;
+pub mod sync;
 pub mod worker;
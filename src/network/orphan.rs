@@ -0,0 +1,221 @@
+use super::peer::PeerId;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Most orphan blocks kept at once
+pub const MAX_ORPHANS: usize = 256;
+/// Most orphan blocks kept from a single peer
+pub const MAX_ORPHANS_PER_PEER: usize = 64;
+/// How long an orphan block waits for its parent before it is dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    peer: PeerId, // peer the block came from
+    received: Instant,
+}
+
+/// Blocks whose parent is not in the blockchain yet, waiting for it to arrive.
+///
+/// Orphans are indexed by parent, so any number of blocks sharing a missing parent are kept. The
+/// pool is bounded in total and per peer, and orphans that wait longer than the expiry are dropped.
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>, // key = hash of orphan
+    children: HashMap<H256, Vec<H256>>, // key = missing parent, value = hashes of its orphans
+    per_peer: HashMap<PeerId, usize>, // number of orphans kept from each peer
+    max_orphans: usize,
+    max_per_peer: usize,
+    expiry: Duration,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrphanPool {
+    /// Create an orphan pool with the default limits
+    pub fn new() -> Self {
+        Self::with_limits(MAX_ORPHANS, MAX_ORPHANS_PER_PEER, ORPHAN_EXPIRY)
+    }
+
+    /// Create an orphan pool with custom limits
+    pub fn with_limits(max_orphans: usize, max_per_peer: usize, expiry: Duration) -> Self {
+        Self {
+            orphans: HashMap::new(),
+            children: HashMap::new(),
+            per_peer: HashMap::new(),
+            max_orphans,
+            max_per_peer,
+            expiry,
+        }
+    }
+
+    /// Keep an orphan received from a peer. Returns false if the block is already kept or the
+    /// peer has reached its limit; if the pool is full the oldest orphan makes room.
+    pub fn insert(&mut self, block: Block, peer: PeerId) -> bool {
+        self.expire(Instant::now());
+
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) || self.max_orphans == 0 {
+            return false;
+        }
+        if self.peer_count(&peer) >= self.max_per_peer {
+            return false;
+        }
+        if self.orphans.len() >= self.max_orphans {
+            let oldest = self.orphans.iter()
+                .min_by_key(|(_, orphan)| orphan.received)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+
+        self.children.entry(block.get_parent()).or_default().push(hash);
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        self.orphans.insert(hash, Orphan { block, peer, received: Instant::now() });
+        true
+    }
+
+    /// Remove and return every orphan whose parent is this block
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).map(|orphan| orphan.block).collect()
+    }
+
+    /// The block to request so that this block can be connected: the parent of the oldest
+    /// ancestor of it that is kept in the pool
+    pub fn missing_ancestor(&self, block: &Block) -> H256 {
+        let mut parent = block.get_parent();
+        while let Some(orphan) = self.orphans.get(&parent) {
+            parent = orphan.block.get_parent();
+        }
+        parent
+    }
+
+    /// Check whether a block is kept as an orphan
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Number of orphans kept from a peer
+    pub fn peer_count(&self, peer: &PeerId) -> usize {
+        self.per_peer.get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    // drop orphans received more than the expiry before now
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self.orphans.iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received) >= self.expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    // remove one orphan, keeping the parent index and peer counts in sync
+    fn remove(&mut self, hash: &H256) -> Option<Orphan> {
+        let orphan = self.orphans.remove(hash)?;
+
+        let parent = orphan.block.get_parent();
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        Some(orphan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    fn peer(id: u64) -> PeerId {
+        PeerId(id)
+    }
+
+    #[test]
+    fn keeps_siblings_and_connects_them() {
+        let mut pool = OrphanPool::new();
+        let missing = generate_random_block(&H256::from([0; 32]));
+        let a = generate_random_block(&missing.hash());
+        let b = generate_random_block(&missing.hash());
+        let a_child = generate_random_block(&a.hash());
+        assert!(pool.insert(a.clone(), peer(1)));
+        assert!(pool.insert(b.clone(), peer(1)));
+        assert!(pool.insert(a_child.clone(), peer(2)));
+        assert!(!pool.insert(a.clone(), peer(1)));
+        assert_eq!(pool.len(), 3);
+
+        // the whole branch waits on the same block
+        assert_eq!(pool.missing_ancestor(&a_child), missing.hash());
+        assert_eq!(pool.missing_ancestor(&b), missing.hash());
+
+        let mut children: Vec<H256> = pool.take_children(&missing.hash()).iter().map(|b| b.hash()).collect();
+        children.sort();
+        let mut expected = vec![a.hash(), b.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert_eq!(pool.peer_count(&peer(1)), 0);
+
+        let grandchildren = pool.take_children(&a.hash());
+        assert_eq!(grandchildren.len(), 1);
+        assert_eq!(grandchildren[0].hash(), a_child.hash());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let mut pool = OrphanPool::with_limits(3, 2, ORPHAN_EXPIRY);
+        let parent = H256::from([1; 32]);
+        let blocks: Vec<Block> = (0..4).map(|_| generate_random_block(&parent)).collect();
+
+        // per peer
+        assert!(pool.insert(blocks[0].clone(), peer(1)));
+        assert!(pool.insert(blocks[1].clone(), peer(1)));
+        assert!(!pool.insert(blocks[2].clone(), peer(1)));
+        assert_eq!(pool.peer_count(&peer(1)), 2);
+
+        // in total, the oldest one makes room
+        assert!(pool.insert(blocks[2].clone(), peer(2)));
+        assert!(pool.insert(blocks[3].clone(), peer(3)));
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.contains(&blocks[0].hash()));
+        assert_eq!(pool.peer_count(&peer(1)), 1);
+    }
+
+    #[test]
+    fn drops_expired_orphans() {
+        let mut pool = OrphanPool::with_limits(10, 10, Duration::from_secs(60));
+        let block = generate_random_block(&H256::from([1; 32]));
+        assert!(pool.insert(block.clone(), peer(1)));
+
+        pool.expire(Instant::now() + Duration::from_secs(61));
+        assert!(pool.is_empty());
+        assert!(pool.take_children(&block.get_parent()).is_empty());
+        assert_eq!(pool.peer_count(&peer(1)), 0);
+    }
+}
@@ -1,57 +1,183 @@
 use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use log::{debug, trace};
+use smol::channel::{self, TrySendError};
 use smol::Async;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
+    id: PeerId,
+    info: PeerInfo,
+    queue_size: usize,
+    policy: SlowPeerPolicy,
+) -> std::io::Result<(channel::Receiver<Vec<u8>>, Handle)> {
+    let (write_sender, write_receiver) = channel::bounded(queue_size);
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
+        id,
         write_queue: write_sender,
+        policy,
+        dropped: Arc::new(AtomicU64::new(0)),
         addr,
+        info,
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+/// What happens to a message for a peer whose write queue is full, because the peer reads slower
+/// than it is written to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SlowPeerPolicy {
+    /// Drop the message and keep the connection
+    Drop,
+    /// Drop the message and disconnect once the queued messages are written
+    Disconnect,
+}
+
+impl std::str::FromStr for SlowPeerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SlowPeerPolicy::Drop),
+            "disconnect" => Ok(SlowPeerPolicy::Disconnect),
+            _ => Err(format!("unknown slow peer policy {}, expected drop or disconnect", s)),
+        }
+    }
+}
+
+/// Identity of a connection, assigned by the server when the peer registers.
+///
+/// Unlike the socket address, an id is never reused, so a message or report meant for a peer that
+/// reconnected from the same address does not reach the new connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub u64);
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer#{}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// What a peer announced in its version message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub protocol_version: u32, // negotiated: the lower of both sides' versions
+    pub best_height: u128, // height of the peer's tip at handshake
+    pub listen_addr: std::net::SocketAddr,
+    pub services: u64,
+    pub direction: Direction,
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
+    id: PeerId,
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    write_queue: channel::Sender<Vec<u8>>,
+    policy: SlowPeerPolicy,
+    dropped: Arc<AtomicU64>, // messages dropped because the queue was full
+    info: PeerInfo,
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: channel::Receiver<Vec<u8>>
 }
 
 impl Handle {
+    /// Queue a message for the peer without waiting; if the queue is full, the message is dropped
+    /// and the slow peer policy applies
     pub fn write(&mut self, msg: Message) {
         let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        match self.write_queue.try_send(buffer) {
+            Ok(()) => {}
+            Err(TrySendError::Closed(_)) => trace!("Trying to send to disconnected peer"),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                match self.policy {
+                    SlowPeerPolicy::Drop => trace!("Write queue of {} is full, dropping message", self.id),
+                    SlowPeerPolicy::Disconnect => {
+                        debug!("Write queue of {} is full, disconnecting", self.id);
+                        self.disconnect();
+                    }
+                }
             }
-        });
+        }
+    }
+
+    /// Close the connection once the messages already queued are written
+    pub fn disconnect(&mut self) {
+        self.write_queue.close();
+    }
+
+    /// Messages waiting to be written to the peer
+    pub fn queued(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    /// Messages dropped because the write queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    pub fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+        Self::test_handle_with(channel::unbounded(), SlowPeerPolicy::Disconnect)
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(id: PeerId, addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
+        let (mut handle, receiver) = Self::test_handle();
+        handle.id = id;
+        handle.addr = addr;
+        handle.info.listen_addr = addr;
+        (handle, receiver)
+    }
+
+    #[cfg(any(test,test_utilities))]
+    fn test_handle_with(
+        (s, r): (channel::Sender<Vec<u8>>, channel::Receiver<Vec<u8>>),
+        policy: SlowPeerPolicy,
+    ) -> (Handle, TestReceiver) {
+        let addr = std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321);
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            id: PeerId(0),
+            addr,
             write_queue: s,
+            policy,
+            dropped: Arc::new(AtomicU64::new(0)),
+            info: PeerInfo {
+                protocol_version: super::message::PROTOCOL_VERSION,
+                best_height: 0,
+                listen_addr: addr,
+                services: super::message::SERVICE_FULL_NODE,
+                direction: Direction::Incoming,
+            },
         },
         TestReceiver {
             r
@@ -62,8 +188,35 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
+        let bytes = smol::block_on(self.r.recv()).unwrap();
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_applies_slow_peer_policy() {
+        let (mut handle, mut receiver) = Handle::test_handle_with(channel::bounded(2), SlowPeerPolicy::Drop);
+        for i in 0..3 {
+            handle.write(Message::Ping(i.to_string()));
+        }
+        assert_eq!(handle.queued(), 2);
+        assert_eq!(handle.dropped(), 1);
+        assert!(matches!(receiver.recv(), Message::Ping(nonce) if nonce == "0"));
+        handle.write(Message::Ping("3".to_string()));
+        assert_eq!(handle.dropped(), 1);
+
+        // a disconnected peer still gets what was queued before
+        let (mut handle, mut receiver) = Handle::test_handle_with(channel::bounded(1), SlowPeerPolicy::Disconnect);
+        handle.write(Message::Ping("0".to_string()));
+        handle.write(Message::Ping("1".to_string()));
+        assert_eq!(handle.dropped(), 1);
+        assert!(handle.write_queue.is_closed());
+        assert!(matches!(receiver.recv(), Message::Ping(nonce) if nonce == "0"));
+        assert!(smol::block_on(receiver.r.recv()).is_err());
+    }
+}
@@ -1,25 +1,84 @@
-use crate::types::address::Address;
-use super::peer;
-use super::message;
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+use super::address_book::{self, AddressBook, MAX_ADDR_MESSAGE};
+use super::ban::{BanList, Misbehavior, BAN_THRESHOLD};
+use super::peer::{self, PeerId, SlowPeerPolicy};
+use super::message::{self, Message};
+use super::sync;
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
+use futures::channel::oneshot;
+use smol::{Async, Executor, Timer};
 use log::{debug, info, trace};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
+/// Longest a peer may take to complete the version handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the number of outbound peers is checked and the address book saved
+pub const MAINTAIN_INTERVAL: Duration = Duration::from_secs(10);
+/// Outbound peers kept connected by default
+pub const DEFAULT_TARGET_OUTBOUND: usize = 8;
+/// Largest frame accepted from a peer by default
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+/// Messages queued for a peer by default before the slow peer policy applies
+pub const DEFAULT_WRITE_QUEUE_SIZE: usize = 1024;
+// largest frame accepted before the handshake completes; version messages are far smaller
+const MAX_HANDSHAKE_FRAME: usize = 1024;
+
+/// Settings of the P2P server.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Outbound peers to keep connected, picked from the address book
+    pub target_outbound: usize,
+    /// File the address book is kept in across restarts; in memory only if none
+    pub address_book: Option<PathBuf>,
+    /// Largest frame accepted from a peer; a peer announcing a longer one is banned
+    pub max_frame_size: usize,
+    /// Messages queued for each peer before the slow peer policy applies
+    pub write_queue_size: usize,
+    /// What happens to a message for a peer whose write queue is full
+    pub slow_peer_policy: SlowPeerPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target_outbound: DEFAULT_TARGET_OUTBOUND,
+            address_book: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            write_queue_size: DEFAULT_WRITE_QUEUE_SIZE,
+            slow_peer_policy: SlowPeerPolicy::Disconnect,
+        }
+    }
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    config: Config,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let address_book = match &config.address_book {
+        Some(path) => AddressBook::open(path)?,
+        None => AddressBook::new(),
+    };
+    let address_book = Arc::new(Mutex::new(address_book));
+    let bans = Arc::new(Mutex::new(BanList::new()));
+    let directory = Arc::new(Mutex::new(HashMap::new()));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        address_book: Arc::clone(&address_book),
+        bans: Arc::clone(&bans),
+        directory: Arc::clone(&directory),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
@@ -27,16 +86,62 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        blockchain: Arc::clone(blockchain),
+        address_book,
+        bans,
+        directory,
+        next_peer_id: 0,
+        pending: HashSet::new(),
+        target_outbound: config.target_outbound,
+        max_frame_size: config.max_frame_size,
+        write_queue_size: config.write_queue_size,
+        slow_peer_policy: config.slow_peer_policy,
     };
     Ok((ctx, handle))
 }
 
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: std::collections::HashMap<PeerId, peer::Handle>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>, // announced in version messages
+    address_book: Arc<Mutex<AddressBook>>, // shared with the handles
+    bans: Arc<Mutex<BanList>>, // shared with the handles
+    directory: Arc<Mutex<HashMap<PeerId, Connected>>>, // shared with the handles
+    next_peer_id: u64, // id of the next peer to register
+    pending: HashSet<std::net::SocketAddr>, // outbound connections not registered yet
+    target_outbound: usize,
+    max_frame_size: usize,
+    write_queue_size: usize,
+    slow_peer_policy: SlowPeerPolicy,
+}
+
+// a registered peer, as listed by the handles
+struct Connected {
+    handle: peer::Handle,
+    ban_score: u32,
+}
+
+// what this node announces in its version message
+#[derive(Clone, Copy)]
+struct LocalVersion {
+    genesis_hash: H256,
+    best_height: u128,
+    listen_addr: net::SocketAddr,
+}
+
+impl LocalVersion {
+    fn message(&self) -> Message {
+        Message::Version {
+            protocol_version: message::PROTOCOL_VERSION,
+            genesis_hash: self.genesis_hash,
+            best_height: self.best_height,
+            listen_addr: self.listen_addr,
+            services: message::SERVICE_FULL_NODE,
+        }
+    }
 }
 
 impl Context {
@@ -46,6 +151,7 @@ impl Context {
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
+        let maintain_chan = self.control_sender.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -57,6 +163,10 @@ impl Context {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
+        ex.spawn(async move {
+            Self::maintain_loop(maintain_chan).await;
+        })
+            .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
@@ -76,72 +186,232 @@ impl Context {
         }
     }
 
+    /// the loop that periodically tops up outbound peers
+    async fn maintain_loop(control_chan: smol::channel::Sender<ControlSignal>) {
+        loop {
+            if control_chan.send(ControlSignal::MaintainOutbound).await.is_err() {
+                break;
+            }
+            Timer::after(MAINTAIN_INTERVAL).await;
+        }
+    }
+
     async fn dispatch_control(mut self, ex: Arc<Executor<'_>>) -> std::io::Result<()> {
         // read the next control signal
         while let Ok(ctrl) = self.control_chan.recv().await {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    if self.bans.lock().unwrap().is_banned(&addr.ip(), address_book::now()) {
+                        let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{} is banned", addr.ip()));
+                        let _ = result_chan.send(Err(e));
+                        continue;
+                    }
+                    self.start_connect(addr, Some(result_chan), ex.clone());
+                }
+                ControlSignal::ConnectFailed(addr) => {
+                    trace!("Processing ConnectFailed({})", addr);
+                    self.pending.remove(&addr);
+                    self.address_book.lock().unwrap().failed(&addr);
+                }
+                ControlSignal::MaintainOutbound => {
+                    trace!("Processing MaintainOutbound command");
+                    self.maintain_outbound(ex.clone());
+                    if let Err(e) = self.address_book.lock().unwrap().save() {
+                        debug!("Error saving address book: {}", e);
+                    }
                 }
-                ControlSignal::BroadcastMessage(msg) => {
+                ControlSignal::BroadcastMessage(msg, except) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                    for (id, hd) in self.peers.iter_mut() {
+                        if Some(*id) != except {
+                            hd.write(msg.clone());
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    if let Ok(addr) = stream.get_ref().peer_addr() {
+                        if self.bans.lock().unwrap().is_banned(&addr.ip(), address_book::now()) {
+                            info!("Refusing banned peer {}", addr);
+                            continue;
+                        }
+                    }
+                    let local = self.local_version();
+                    let control_chan = self.control_sender.clone();
+                    ex.spawn(async move {
+                        let addr = stream.get_ref().peer_addr();
+                        match handshake(&stream, local, peer::Direction::Incoming).await {
+                            Ok(info) => control_chan
+                                .send(ControlSignal::Handshaken(stream, info, None))
+                                .await
+                                .unwrap(),
+                            Err(e) => info!("Dropping incoming peer {:?}: {}", addr, e),
+                        }
+                    })
+                        .detach();
                 }
-                ControlSignal::DroppedPeer(addr) => {
-                    trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                ControlSignal::Handshaken(stream, info, result_chan) => {
+                    trace!("Processing Handshaken command");
+                    debug!("Handshake done with protocol version {}, peer at height {}", info.protocol_version, info.best_height);
+                    let handle = self.register(stream, info, ex.clone());
+                    match result_chan {
+                        Some(result_chan) => {
+                            let _ = result_chan.send(handle);
+                        }
+                        None => {
+                            if let Err(e) = handle {
+                                debug!("Error registering incoming peer: {}", e);
+                            }
+                        }
+                    }
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::Misbehaved(id, misbehavior) => {
+                    trace!("Processing Misbehaved({}, {})", id, misbehavior);
+                    self.misbehaved(id, misbehavior);
+                }
+                ControlSignal::DroppedPeer(id) => {
+                    trace!("Processing DroppedPeer({})", id);
+                    if let Some(peer) = self.peers.remove(&id) {
+                        info!("Peer {} at {} disconnected", id, peer.addr());
+                    }
+                    self.directory.lock().unwrap().remove(&id);
+                    // replace a lost outbound peer right away
+                    self.maintain_outbound(ex.clone());
+                }
+                ControlSignal::SendToPeer((id, msg)) => {
+                    trace!("Processing SendToPeer({})", id);
+                    match self.peers.get_mut(&id) {
+                        Some(peer) => peer.write(msg),
+                        None => trace!("Dropping message to disconnected peer {}", id),
+                    }
                 }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
+    /// Add to the ban score of a peer, banning and disconnecting it at `BAN_THRESHOLD`. A peer on
+    /// a loopback address is only disconnected, since a ban would cut off every local node.
+    fn misbehaved(&mut self, id: PeerId, misbehavior: Misbehavior) {
+        // reports may arrive after the peer is gone
+        let (addr, score) = match self.directory.lock().unwrap().get_mut(&id) {
+            Some(connected) => {
+                connected.ban_score = connected.ban_score.saturating_add(misbehavior.score());
+                (*connected.handle.addr(), connected.ban_score)
+            }
+            None => return,
+        };
+        debug!("Peer {} at {} sent {}, ban score {}", id, addr, misbehavior, score);
+        if score < BAN_THRESHOLD {
+            return;
+        }
+        if addr.ip().is_loopback() {
+            info!("Disconnecting local peer {} for misbehaving", addr);
+            if let Some(peer) = self.peers.get_mut(&id) {
+                peer.disconnect();
+            }
+            return;
+        }
+        info!("Banning peer {} for misbehaving", addr.ip());
+        self.bans.lock().unwrap().ban(addr.ip(), address_book::now());
+        // every connection from the address goes
+        for peer in self.peers.values_mut().filter(|peer| peer.addr().ip() == addr.ip()) {
+            peer.disconnect();
+        }
+    }
+
+    /// Connect and shake hands in the background, then come back to register the peer
+    fn start_connect(
         &mut self,
-        addr: &std::net::SocketAddr,
+        addr: std::net::SocketAddr,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
         ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
-        debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+    ) {
+        self.pending.insert(addr);
+        self.address_book.lock().unwrap().attempt(&addr, address_book::now());
+        let local = self.local_version();
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            match Self::connect(&addr, local).await {
+                Ok((stream, info)) => control_chan
+                    .send(ControlSignal::Handshaken(stream, info, result_chan))
+                    .await
+                    .unwrap(),
+                Err(e) => {
+                    debug!("Error connecting to peer {}: {}", addr, e);
+                    control_chan.send(ControlSignal::ConnectFailed(addr)).await.unwrap();
+                    if let Some(result_chan) = result_chan {
+                        let _ = result_chan.send(Err(e));
+                    }
+                }
+            }
+        })
+            .detach();
+    }
+
+    /// Connect to peers from the address book until the target number of outbound peers is
+    /// connected or connecting
+    fn maintain_outbound(&mut self, ex: Arc<Executor<'_>>) {
+        let outbound = self.peers.values()
+            .filter(|peer| peer.info().direction == peer::Direction::Outgoing)
+            .count() + self.pending.len();
+        let now = address_book::now();
+        for _ in outbound..self.target_outbound {
+            // skip ourselves, banned peers and peers we already talk to, in either direction
+            let selected = {
+                let bans = self.bans.lock().unwrap();
+                self.address_book.lock().unwrap().select(|addr| {
+                    *addr == self.addr
+                        || bans.is_banned(&addr.ip(), now)
+                        || self.pending.contains(addr)
+                        || self.peers.values().any(|peer| peer.addr() == addr || peer.info().listen_addr == *addr)
+                }, now)
+            };
+            match selected {
+                Some(addr) => self.start_connect(addr, None, ex.clone()),
+                None => break,
+            }
+        }
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+    fn local_version(&self) -> LocalVersion {
+        let blockchain = self.blockchain.lock().unwrap();
+        LocalVersion {
+            genesis_hash: blockchain.genesis_hash(),
+            best_height: blockchain.height(blockchain.tip()).unwrap_or(0),
+            listen_addr: self.addr,
+        }
     }
 
-    async fn accept(
-        &mut self,
-        stream: Async<net::TcpStream>,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
-        Ok(())
+    /// Connect to a peer and shake hands with it
+    async fn connect(
+        addr: &std::net::SocketAddr,
+        local: LocalVersion,
+    ) -> std::io::Result<(Async<net::TcpStream>, peer::PeerInfo)> {
+        debug!("Establishing connection to peer {}", addr);
+        let stream = Async::<std::net::TcpStream>::connect(*addr).await?;
+        let info = handshake(&stream, local, peer::Direction::Outgoing).await?;
+        Ok((stream, info))
     }
 
-    async fn register(
+    /// Start exchanging messages with a peer that completed the handshake
+    fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        info: peer::PeerInfo,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let id = PeerId(self.next_peer_id);
+        self.next_peer_id += 1;
+        let (write_queue, mut handle) = peer::new(&stream, id, info, self.write_queue_size, self.slow_peer_policy)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
-        let handle_copy = handle.clone();
+        let mut handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
+        let report_chan = self.control_sender.clone();
+        let max_frame_size = self.max_frame_size;
         let addr = stream.get_ref().peer_addr()?;
 
         // start the reactor for this peer
@@ -160,6 +430,12 @@ impl Context {
                         break;
                     }
                 };
+                // a length beyond the limit is never read, so a peer cannot make us allocate it
+                if msg_size as usize > max_frame_size {
+                    debug!("Peer {} announced a frame of {} bytes", addr, msg_size);
+                    let _ = report_chan.send(ControlSignal::Misbehaved(id, Misbehavior::OversizedFrame)).await;
+                    break;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -180,17 +456,18 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or sent something we stopped reading at; the writing task
+            // finishes the queue and closes the connection
+            handle_copy.disconnect();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
+            // first, get a message to write from the queue; it closes when the peer is
+            // disconnected on purpose
+            while let Ok(new_msg) = write_queue.recv().await {
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
 
@@ -214,23 +491,132 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected; closing the socket also stops the reading task
+            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
-                .send(ControlSignal::DroppedPeer(addr))
+                .send(ControlSignal::DroppedPeer(id))
                 .await
                 .unwrap();
         })
             .detach();
 
+        match handle.info().direction {
+            peer::Direction::Outgoing => {
+                self.pending.remove(&addr);
+                self.address_book.lock().unwrap().good(&addr, address_book::now());
+                // learn about more peers, and catch up with this one's chain, headers first
+                handle.write(Message::GetAddr);
+                let get_headers = sync::get_headers(&self.blockchain.lock().unwrap());
+                handle.write(get_headers);
+            }
+            peer::Direction::Incoming => {
+                // the peer accepts connections where it said, on the address it came from if it
+                // listens on all interfaces
+                let mut listen_addr = handle.info().listen_addr;
+                if listen_addr.ip().is_unspecified() {
+                    listen_addr.set_ip(addr.ip());
+                }
+                self.address_book.lock().unwrap().add(&[listen_addr], address_book::now());
+                // and the peers already connected learn about it
+                for other in self.peers.values_mut() {
+                    other.write(Message::Addr(vec![listen_addr]));
+                }
+            }
+        }
+
         // insert the peer handle so that we can broadcast to this guy later
-        self.peers.insert(addr, handle.clone());
+        self.directory.lock().unwrap().insert(id, Connected { handle: handle.clone(), ban_score: 0 });
+        self.peers.insert(id, handle.clone());
         Ok(handle)
     }
 }
 
+/// Exchange version messages on a new connection and return what the peer announced.
+///
+/// Both sides send `Version` first and then acknowledge the other's with `VerAck`. The connection
+/// is refused if the peer speaks a protocol older than `MIN_PROTOCOL_VERSION`, is on another
+/// genesis block, sends anything else, or does not finish within `HANDSHAKE_TIMEOUT`.
+async fn handshake(
+    stream: &Async<net::TcpStream>,
+    local: LocalVersion,
+    direction: peer::Direction,
+) -> io::Result<peer::PeerInfo> {
+    let exchange = async {
+        let mut stream = stream;
+        write_frame(&mut stream, &local.message()).await?;
+        let info = match read_frame(&mut stream).await? {
+            Message::Version { protocol_version, genesis_hash, best_height, listen_addr, services } => {
+                if protocol_version < message::MIN_PROTOCOL_VERSION {
+                    return Err(refused(format!("protocol version {} is too old", protocol_version)));
+                }
+                if genesis_hash != local.genesis_hash {
+                    return Err(refused(format!("peer is on genesis block {}", genesis_hash)));
+                }
+                peer::PeerInfo {
+                    protocol_version: std::cmp::min(protocol_version, message::PROTOCOL_VERSION),
+                    best_height,
+                    listen_addr,
+                    services,
+                    direction,
+                }
+            }
+            _ => return Err(refused("expected a version message".to_string())),
+        };
+        write_frame(&mut stream, &Message::VerAck).await?;
+        match read_frame(&mut stream).await? {
+            Message::VerAck => Ok(info),
+            _ => Err(refused("expected a version acknowledgement".to_string())),
+        }
+    };
+    let timeout = async {
+        Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+    };
+    smol::future::or(exchange, timeout).await
+}
+
+fn refused(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// write one length prefixed message
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, msg: &Message) -> io::Result<()> {
+    let payload = bincode::serialize(msg).unwrap();
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await
+}
+
+// read one length prefixed message of the handshake
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    let mut size_buffer: [u8; 4] = [0; 4];
+    reader.read_exact(&mut size_buffer).await?;
+    let msg_size = u32::from_be_bytes(size_buffer) as usize;
+    if msg_size > MAX_HANDSHAKE_FRAME {
+        return Err(refused(format!("handshake frame of {} bytes", msg_size)));
+    }
+    let mut msg_buffer = vec![0; msg_size];
+    reader.read_exact(&mut msg_buffer).await?;
+    bincode::deserialize(&msg_buffer).map_err(|e| refused(e.to_string()))
+}
+
+/// A connected peer, as listed by `Handle::peers`.
+#[derive(Clone, Debug)]
+pub struct PeerSummary {
+    pub id: PeerId,
+    pub addr: std::net::SocketAddr,
+    pub info: peer::PeerInfo,
+    pub ban_score: u32,
+    pub queued: usize, // messages waiting to be written to the peer
+    pub dropped: u64, // messages dropped because its write queue was full
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    address_book: Arc<Mutex<AddressBook>>,
+    bans: Arc<Mutex<BanList>>,
+    directory: Arc<Mutex<HashMap<PeerId, Connected>>>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -242,10 +628,28 @@ impl TestReceiver {
         let sig = smol::block_on(self.control_chan.recv()).unwrap();
         match sig {
             // in this test, only return broadcast msg
-            ControlSignal::BroadcastMessage(msg) => Some(msg),
+            ControlSignal::BroadcastMessage(msg, _) => Some(msg),
+            _ => None,
+        }
+    }
+
+    pub fn recv_report(&self) -> Option<(PeerId, Misbehavior)> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::Misbehaved(id, misbehavior) => Some((id, misbehavior)),
+            _ => None,
+        }
+    }
+
+    /// The next relayed message, with the peer it must not go back to
+    pub fn recv_relay(&self) -> Option<(message::Message, Option<PeerId>)> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::BroadcastMessage(msg, except) => Some((msg, except)),
             _ => None,
         }
     }
+
 }
 
 impl Handle {
@@ -260,17 +664,78 @@ impl Handle {
     }
 
     pub fn broadcast(&self, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
+        smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg, None))).unwrap();
+    }
+
+    /// Send a message to every peer but the one it came from
+    pub fn relay(&self, msg: message::Message, source: PeerId) {
+        smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg, Some(source)))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
+    /// Send a message to one peer; it is dropped if the peer is gone
+    pub fn send(&self, receiver: PeerId, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Peers currently connected, in the order they registered
+    pub fn peers(&self) -> Vec<PeerSummary> {
+        let mut peers: Vec<PeerSummary> = self.directory.lock().unwrap().iter()
+            .map(|(id, connected)| PeerSummary {
+                id: *id,
+                addr: *connected.handle.addr(),
+                info: connected.handle.info().clone(),
+                ban_score: connected.ban_score,
+                queued: connected.handle.queued(),
+                dropped: connected.handle.dropped(),
+            })
+            .collect();
+        peers.sort_by_key(|summary| summary.id);
+        peers
+    }
+
+    /// Remember peer addresses announced in an `Addr` message
+    pub fn learn_addresses(&self, addrs: &[std::net::SocketAddr]) {
+        let addrs = &addrs[..addrs.len().min(MAX_ADDR_MESSAGE)];
+        self.address_book.lock().unwrap().add(addrs, address_book::now());
+    }
+
+    /// Report a rule violation of a peer, adding to its ban score
+    pub fn report(&self, peer: PeerId, misbehavior: Misbehavior) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaved(peer, misbehavior))).unwrap();
+    }
+
+    /// Banned addresses with the unix time their ban ends
+    pub fn bans(&self) -> Vec<(std::net::IpAddr, u64)> {
+        self.bans.lock().unwrap().list(address_book::now())
+    }
+
+    /// Lift the ban of an address, or of all addresses; returns whether anything was banned
+    pub fn unban(&self, ip: Option<std::net::IpAddr>) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match ip {
+            Some(ip) => bans.unban(&ip),
+            None => {
+                let any = !bans.list(address_book::now()).is_empty();
+                bans.clear();
+                any
+            }
+        }
+    }
+
+    /// Known peer addresses to announce in an `Addr` message
+    pub fn known_addresses(&self) -> Vec<std::net::SocketAddr> {
+        self.address_book.lock().unwrap().sample()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {
+            control_chan: s,
+            address_book: Arc::new(Mutex::new(AddressBook::new())),
+            bans: Arc::new(Mutex::new(BanList::new())),
+            directory: Arc::new(Mutex::new(HashMap::new())),
+        };
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -281,8 +746,115 @@ enum ControlSignal {
         std::net::SocketAddr,
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
-    BroadcastMessage(message::Message),
+    BroadcastMessage(message::Message, Option<PeerId>), // skipping the peer, if any
     GetNewPeer(Async<net::TcpStream>),
-    DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    Handshaken(
+        Async<net::TcpStream>,
+        peer::PeerInfo,
+        Option<oneshot::Sender<std::io::Result<peer::Handle>>>, // set for outgoing connections
+    ),
+    DroppedPeer(PeerId),
+    ConnectFailed(std::net::SocketAddr),
+    Misbehaved(PeerId, Misbehavior),
+    MaintainOutbound,
+    SendToPeer((PeerId, message::Message)),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a connected pair of sockets
+    fn socket_pair() -> (Async<net::TcpStream>, Async<net::TcpStream>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let outgoing = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (incoming, _) = listener.accept().unwrap();
+        (Async::new(outgoing).unwrap(), Async::new(incoming).unwrap())
+    }
+
+    fn local(genesis: u8, best_height: u128, port: u16) -> LocalVersion {
+        LocalVersion {
+            genesis_hash: H256::from([genesis; 32]),
+            best_height,
+            listen_addr: net::SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    #[test]
+    fn one_malformed_message_does_not_ban() {
+        let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(key_pair)));
+        let (msg_sink, _msg_source) = smol::channel::unbounded();
+        let (mut ctx, handle) = new(net::SocketAddr::from(([127, 0, 0, 1], 0)), msg_sink, &blockchain, Config::default()).unwrap();
+        let (peer, _peer_receiver) = peer::Handle::test_handle_at(PeerId(0), net::SocketAddr::from(([10, 0, 0, 1], 6000)));
+        ctx.directory.lock().unwrap().insert(peer.id(), Connected { handle: peer.clone(), ban_score: 0 });
+        ctx.peers.insert(peer.id(), peer.clone());
+
+        ctx.misbehaved(peer.id(), Misbehavior::MalformedMessage);
+        assert!(handle.bans().is_empty());
+        assert_eq!(handle.peers()[0].ban_score, Misbehavior::MalformedMessage.score());
+
+        // a peer that keeps sending them is banned
+        while handle.bans().is_empty() {
+            ctx.misbehaved(peer.id(), Misbehavior::MalformedMessage);
+        }
+        assert_eq!(handle.bans()[0].0, peer.addr().ip());
+        assert!(handle.peers()[0].ban_score >= BAN_THRESHOLD);
+    }
+
+    #[test]
+    fn local_peers_are_disconnected_but_not_banned() {
+        let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(key_pair)));
+        let (msg_sink, _msg_source) = smol::channel::unbounded();
+        let (mut ctx, handle) = new(net::SocketAddr::from(([127, 0, 0, 1], 0)), msg_sink, &blockchain, Config::default()).unwrap();
+        let (peer, _peer_receiver) = peer::Handle::test_handle();
+        ctx.directory.lock().unwrap().insert(peer.id(), Connected { handle: peer.clone(), ban_score: 0 });
+        ctx.peers.insert(peer.id(), peer.clone());
+
+        // a block from a node with other retarget parameters does not cross the threshold
+        ctx.misbehaved(peer.id(), Misbehavior::UnexpectedDifficulty);
+        assert!(handle.peers()[0].ban_score < BAN_THRESHOLD);
+
+        while handle.peers()[0].ban_score < BAN_THRESHOLD {
+            ctx.misbehaved(peer.id(), Misbehavior::MalformedMessage);
+        }
+        assert!(handle.bans().is_empty());
+        assert!(peer.is_disconnected());
+    }
+
+    #[test]
+    fn handshake_exchanges_versions() {
+        let (outgoing, incoming) = socket_pair();
+        let (ours, theirs) = smol::block_on(futures::future::join(
+            handshake(&outgoing, local(1, 5, 6000), peer::Direction::Outgoing),
+            handshake(&incoming, local(1, 9, 6001), peer::Direction::Incoming),
+        ));
+        let ours = ours.unwrap();
+        assert_eq!(ours.best_height, 9);
+        assert_eq!(ours.listen_addr.port(), 6001);
+        assert_eq!(ours.direction, peer::Direction::Outgoing);
+        assert_eq!(ours.protocol_version, message::PROTOCOL_VERSION);
+        assert_eq!(theirs.unwrap().best_height, 5);
+    }
+
+    #[test]
+    fn handshake_refuses_other_chains_and_early_traffic() {
+        let (outgoing, incoming) = socket_pair();
+        let (ours, theirs) = smol::block_on(futures::future::join(
+            handshake(&outgoing, local(1, 0, 6000), peer::Direction::Outgoing),
+            handshake(&incoming, local(2, 0, 6001), peer::Direction::Incoming),
+        ));
+        assert_eq!(ours.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(theirs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a peer skipping the handshake is refused
+        let (mut outgoing, incoming) = socket_pair();
+        let (sent, theirs) = smol::block_on(futures::future::join(
+            write_frame(&mut outgoing, &Message::Ping("hello".to_string())),
+            handshake(&incoming, local(1, 0, 6001), peer::Direction::Incoming),
+        ));
+        sent.unwrap();
+        assert_eq!(theirs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,485 @@
+use super::message::Message;
+use super::peer::PeerId;
+use crate::blockchain::difficulty::{self, MAX_ADJUSTMENT};
+use crate::blockchain::validation::{BlockError, MAX_FUTURE_BLOCK_TIME};
+use crate::blockchain::Blockchain;
+use crate::types::block::Header;
+use crate::types::hash::{H256, Hashable};
+use crate::types::uint::U256;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Most headers sent in reply to one `GetHeaders`
+pub const MAX_HEADERS: usize = 2000;
+/// Most block bodies asked from one peer at a time
+pub const BLOCKS_PER_REQUEST: usize = 16;
+/// How long a peer has to deliver requested bodies before they are asked from another peer
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most accepted headers kept while their bodies are missing
+pub const MAX_PENDING_HEADERS: usize = 50 * MAX_HEADERS;
+
+/// Hashes of the longest chain to locate the fork point with a peer: the last ten blocks one by
+/// one, then exponentially further apart, always ending with the genesis block.
+pub fn block_locator(chain: &[H256]) -> Vec<H256> {
+    let mut locator: Vec<H256> = Vec::new();
+    if chain.is_empty() {
+        return locator;
+    }
+    let mut index = chain.len() - 1;
+    let mut step = 1;
+    loop {
+        locator.push(chain[index]);
+        if index == 0 {
+            break;
+        }
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        index = index.saturating_sub(step);
+    }
+    locator
+}
+
+/// Message asking a peer for the headers after our longest chain
+pub fn get_headers(blockchain: &Blockchain) -> Message {
+    let locator = block_locator(&blockchain.all_blocks_in_longest_chain());
+    Message::GetHeaders { locator, stop: H256::default() }
+}
+
+/// Message asking a peer for the headers after a header we already have, when the previous reply
+/// was cut off at `MAX_HEADERS`
+pub fn get_more_headers(blockchain: &Blockchain, last: H256) -> Message {
+    let mut locator = vec![last];
+    locator.extend(block_locator(&blockchain.all_blocks_in_longest_chain()));
+    Message::GetHeaders { locator, stop: H256::default() }
+}
+
+/// Headers of the longest chain following the first locator hash on it, up to and including
+/// `stop` and at most `MAX_HEADERS` of them. Without a common block the headers start after the
+/// genesis block.
+pub fn headers_after(blockchain: &Blockchain, locator: &[H256], stop: H256) -> Vec<Header> {
+    let chain = blockchain.all_blocks_in_longest_chain();
+    let positions: HashMap<H256, usize> = chain.iter().enumerate().map(|(i, hash)| (*hash, i)).collect();
+    let start = locator.iter().find_map(|hash| positions.get(hash)).map_or(1, |i| i + 1);
+
+    let mut headers: Vec<Header> = Vec::new();
+    for hash in chain.iter().skip(start).take(MAX_HEADERS) {
+        if let Ok(block) = blockchain.get_parent_block(*hash) {
+            headers.push(block.get_header());
+        }
+        if *hash == stop {
+            break;
+        }
+    }
+    headers
+}
+
+struct KnownHeader {
+    header: Header,
+    height: u128,
+    work: U256, // work of the header and all its ancestors
+    queued: bool, // whether its body is going to be downloaded
+}
+
+/// Headers-first block download.
+///
+/// Headers from peers are checked for proof of work and linkage before any body is asked for, and
+/// bodies are only downloaded once a branch of accepted headers has more work than the longest
+/// chain. They are then spread over every peer that serves headers, in batches of
+/// `BLOCKS_PER_REQUEST`. Batches not delivered in time go to the next peer asking.
+pub struct HeaderSync {
+    headers: HashMap<H256, KnownHeader>, // accepted headers whose block is not in the blockchain yet
+    queue: VecDeque<H256>, // bodies not asked for yet, lowest first
+    in_flight: HashMap<H256, (PeerId, Instant)>, // bodies asked for, with the peer and when
+    batch_size: usize,
+    timeout: Duration,
+    max_headers: usize,
+}
+
+impl Default for HeaderSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        Self::with_limits(BLOCKS_PER_REQUEST, BLOCK_REQUEST_TIMEOUT, MAX_PENDING_HEADERS)
+    }
+
+    /// Create a header sync with a custom batch size, request timeout and number of headers kept
+    pub fn with_limits(batch_size: usize, timeout: Duration, max_headers: usize) -> Self {
+        Self {
+            headers: HashMap::new(),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            batch_size,
+            timeout,
+            max_headers,
+        }
+    }
+
+    /// Accept headers sent by a peer, ordered from parent to child. Headers are taken up to the
+    /// first invalid one, whose error is returned, or until `max_headers` are kept; otherwise
+    /// returns how many headers were new.
+    pub fn add_headers(&mut self, blockchain: &Blockchain, headers: &[Header]) -> Result<usize, BlockError> {
+        let tip_work = blockchain.total_work(blockchain.tip()).unwrap_or_else(|_| U256::zero());
+        let mut added = 0;
+        for header in headers {
+            let hash = header.hash();
+            if blockchain.is_present(hash) || self.headers.contains_key(&hash) {
+                continue;
+            }
+            if self.headers.len() >= self.max_headers {
+                self.evict_unqueued();
+                if self.headers.len() >= self.max_headers {
+                    break;
+                }
+            }
+            let (height, parent_work) = self.check_header(blockchain, header)?;
+            let work = parent_work.saturating_add(&difficulty::work(&header.get_difficulty()));
+            self.headers.insert(hash, KnownHeader { header: header.clone(), height, work, queued: false });
+            if work > tip_work {
+                self.queue_branch(hash);
+            }
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    // queue the body of a header and those of its ancestors not queued yet, lowest first
+    fn queue_branch(&mut self, hash: H256) {
+        let mut branch: Vec<H256> = Vec::new();
+        let mut next = hash;
+        while let Some(known) = self.headers.get_mut(&next) {
+            if known.queued {
+                break;
+            }
+            known.queued = true;
+            branch.push(next);
+            next = known.header.get_parent();
+        }
+        self.queue.extend(branch.into_iter().rev());
+    }
+
+    // forget headers of branches that never had more work than the longest chain
+    fn evict_unqueued(&mut self) {
+        self.headers.retain(|_, known| known.queued);
+    }
+
+    // the checks of validate_block that need only the header, returning the header's height and
+    // the total work of its parent
+    fn check_header(&self, blockchain: &Blockchain, header: &Header) -> Result<(u128, U256), BlockError> {
+        if header.hash() > header.get_difficulty() {
+            return Err(BlockError::InsufficientProofOfWork);
+        }
+
+        // the parent is either a block or an accepted header
+        let parent = header.get_parent();
+        let (parent_difficulty, parent_timestamp, parent_height, parent_work) = match blockchain.get_parent_block(parent) {
+            Ok(block) => {
+                let height = blockchain.height(parent).map_err(|_| BlockError::UnknownParent(parent))?;
+                let work = blockchain.total_work(parent).map_err(|_| BlockError::UnknownParent(parent))?;
+                (block.get_difficulty(), block.get_timestamp(), height, work)
+            }
+            Err(_) => match self.headers.get(&parent) {
+                Some(known) => (known.header.get_difficulty(), known.header.get_timestamp(), known.height, known.work),
+                None => return Err(BlockError::UnknownParent(parent)),
+            },
+        };
+        let height = parent_height + 1;
+
+        // the exact target needs the timestamps of a whole retarget window, which validate_block
+        // checks once the body arrives; here the target can only move within the retarget bounds
+        let found = header.get_difficulty();
+        if difficulty::is_retarget_height(&blockchain.retarget_params(), height) {
+            let parent_target = U256::from(parent_difficulty);
+            let max_target = U256::from(blockchain.max_difficulty());
+            let easiest = parent_target.checked_mul_u64(MAX_ADJUSTMENT)
+                .map_or(max_target, |target| std::cmp::min(target, max_target));
+            let hardest = parent_target.div_u64(MAX_ADJUSTMENT);
+            let target = U256::from(found);
+            if target < hardest || target > easiest {
+                return Err(BlockError::DifficultyMismatch { expected: parent_difficulty, found });
+            }
+        } else if found != parent_difficulty {
+            return Err(BlockError::DifficultyMismatch { expected: parent_difficulty, found });
+        }
+
+        if header.get_timestamp() <= parent_timestamp {
+            return Err(BlockError::TimestampTooOld { parent: parent_timestamp, found: header.get_timestamp() });
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if header.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::TimestampTooNew { now, found: header.get_timestamp() });
+        }
+
+        Ok((height, parent_work))
+    }
+
+    /// Next batch of bodies to ask a peer for. Empty if nothing is left or the peer is still
+    /// working on its previous batch.
+    pub fn request_blocks(&mut self, peer: PeerId) -> Vec<H256> {
+        self.requeue_expired(Instant::now());
+        if self.in_flight.values().any(|(p, _)| *p == peer) {
+            return Vec::new();
+        }
+
+        let mut wanted: Vec<H256> = Vec::new();
+        while wanted.len() < self.batch_size {
+            let hash = match self.queue.pop_front() {
+                Some(hash) => hash,
+                None => break,
+            };
+            // skip blocks that arrived some other way or were evicted in the meantime
+            if self.headers.contains_key(&hash) && !self.in_flight.contains_key(&hash) {
+                self.in_flight.insert(hash, (peer, Instant::now()));
+                wanted.push(hash);
+            }
+        }
+        wanted
+    }
+
+    // put requests older than the timeout back in front of the queue, lowest first
+    fn requeue_expired(&mut self, now: Instant) {
+        let mut expired: Vec<H256> = self.in_flight.iter()
+            .filter(|(_, (_, asked))| now.duration_since(*asked) >= self.timeout)
+            .map(|(hash, _)| *hash)
+            .collect();
+        expired.sort_by_key(|hash| self.headers.get(hash).map(|known| known.height));
+        for hash in expired.into_iter().rev() {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+    }
+
+    /// Check whether a block is going to be downloaded, so that nobody needs to ask for it
+    pub fn is_expected(&self, hash: &H256) -> bool {
+        self.headers.get(hash).is_some_and(|known| known.queued)
+    }
+
+    /// A requested body arrived, whether or not it could be inserted yet
+    pub fn block_received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+    }
+
+    /// Ask for a body again, because the one received did not match its header
+    pub fn retry(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+        if self.is_expected(hash) {
+            self.queue.push_front(*hash);
+        }
+    }
+
+    /// Stop tracking a block, because it was inserted or turned out invalid
+    pub fn remove(&mut self, hash: &H256) {
+        self.headers.remove(hash);
+        self.in_flight.remove(hash);
+    }
+
+    /// Number of accepted headers whose block is not in the blockchain yet
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::{self, Block};
+    use crate::types::merkle::MerkleTree;
+    use ring::signature::Ed25519KeyPair;
+    use std::sync::OnceLock;
+
+    fn blockchain() -> Blockchain {
+        Blockchain::new(Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap())
+    }
+
+    fn peer(id: u64) -> PeerId {
+        PeerId(id)
+    }
+
+    // empty block on top of parent with valid proof of work
+    fn mine_empty(parent: &Block) -> Block {
+        mine_at(parent, parent.get_timestamp() + 1)
+    }
+
+    fn mine_at(parent: &Block, timestamp: u128) -> Block {
+        let merkle_root = MerkleTree::new(&Vec::<H256>::new()).root();
+        let mut nonce = 0;
+        loop {
+            let header = block::build_header(parent.hash(), nonce, parent.get_difficulty(), timestamp, merkle_root, parent.get_state_root());
+            let new_block = block::build_block(header, block::build_content(vec![]));
+            if new_block.hash() <= new_block.get_difficulty() {
+                return new_block;
+            }
+            nonce += 1;
+        }
+    }
+
+    // a blockchain with `count` blocks on top of genesis, mined once for all tests
+    fn mined_chain(count: usize) -> Blockchain {
+        static BLOCKS: OnceLock<Vec<Block>> = OnceLock::new();
+        let blocks = BLOCKS.get_or_init(|| {
+            let genesis = blockchain();
+            let mut blocks = vec![genesis.get_parent_block(genesis.tip()).unwrap()];
+            for _ in 0..5 {
+                blocks.push(mine_empty(blocks.last().unwrap()));
+            }
+            blocks.split_off(1)
+        });
+
+        let mut blockchain = blockchain();
+        for block in blocks.iter().take(count) {
+            blockchain.insert(block);
+        }
+        blockchain
+    }
+
+    #[test]
+    fn locator_is_dense_near_tip_and_ends_at_genesis() {
+        let chain: Vec<H256> = (0..100u8).map(|i| H256::from([i; 32])).collect();
+        let locator = block_locator(&chain);
+        let expected: Vec<u8> = vec![99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 88, 84, 76, 60, 28, 0];
+        assert_eq!(locator, expected.into_iter().map(|i| H256::from([i; 32])).collect::<Vec<H256>>());
+        assert_eq!(block_locator(&chain[..1]), vec![chain[0]]);
+    }
+
+    #[test]
+    fn headers_follow_the_common_block() {
+        let remote = mined_chain(5);
+        let chain = remote.all_blocks_in_longest_chain();
+
+        let headers = headers_after(&remote, &[chain[2]], H256::default());
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(hashes, chain[3..].to_vec());
+
+        let headers = headers_after(&remote, &[H256::from([9; 32]), chain[0]], chain[3]);
+        let hashes: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(hashes, chain[1..4].to_vec());
+    }
+
+    #[test]
+    fn accepts_valid_header_chains_only() {
+        let remote = mined_chain(3);
+        let local = blockchain();
+        let headers = headers_after(&remote, &block_locator(&local.all_blocks_in_longest_chain()), H256::default());
+        assert_eq!(headers.len(), 3);
+
+        // out of order, the first header has no known parent
+        let mut sync = HeaderSync::new();
+        assert_eq!(sync.add_headers(&local, &headers[1..]), Err(BlockError::UnknownParent(headers[0].hash())));
+        assert!(sync.is_empty());
+
+        // without proof of work
+        let genesis = local.get_parent_block(local.tip()).unwrap();
+        let forged = loop {
+            let header = block::build_header(genesis.hash(), rand::random(), genesis.get_difficulty(), 1, H256::default(), H256::default());
+            if header.hash() > header.get_difficulty() {
+                break header;
+            }
+        };
+        assert_eq!(sync.add_headers(&local, &[forged]), Err(BlockError::InsufficientProofOfWork));
+
+        assert_eq!(sync.add_headers(&local, &headers), Ok(3));
+        assert_eq!(sync.add_headers(&local, &headers), Ok(0));
+        assert!(headers.iter().all(|h| sync.is_expected(&h.hash())));
+    }
+
+    #[test]
+    fn bodies_are_spread_over_peers() {
+        let remote = mined_chain(5);
+        let local = blockchain();
+        let headers = headers_after(&remote, &block_locator(&local.all_blocks_in_longest_chain()), H256::default());
+        let chain = remote.all_blocks_in_longest_chain();
+
+        let mut sync = HeaderSync::with_limits(3, BLOCK_REQUEST_TIMEOUT, MAX_PENDING_HEADERS);
+        sync.add_headers(&local, &headers).unwrap();
+
+        let first = sync.request_blocks(peer(1));
+        assert_eq!(first, chain[1..4].to_vec());
+        // one batch per peer at a time
+        assert!(sync.request_blocks(peer(1)).is_empty());
+        let second = sync.request_blocks(peer(2));
+        assert_eq!(second, chain[4..].to_vec());
+
+        for hash in first.iter() {
+            sync.block_received(hash);
+        }
+        assert!(sync.request_blocks(peer(1)).is_empty());
+    }
+
+    #[test]
+    fn bodies_wait_for_more_work_than_the_tip() {
+        let local = mined_chain(2);
+        let genesis = local.get_parent_block(local.genesis_hash()).unwrap();
+
+        // a fork of genesis with a different timestamp, one block longer than the local chain
+        let mut fork = vec![mine_at(&genesis, genesis.get_timestamp() + 2)];
+        for _ in 0..2 {
+            fork.push(mine_empty(fork.last().unwrap()));
+        }
+        let headers: Vec<Header> = fork.iter().map(|b| b.get_header()).collect();
+
+        // as much work as the tip is not enough
+        let mut sync = HeaderSync::new();
+        assert_eq!(sync.add_headers(&local, &headers[..2]), Ok(2));
+        assert!(sync.request_blocks(peer(1)).is_empty());
+        assert!(!sync.is_expected(&fork[0].hash()));
+
+        // the whole branch is fetched once it has more work
+        assert_eq!(sync.add_headers(&local, &headers[2..]), Ok(1));
+        let hashes: Vec<H256> = fork.iter().map(|b| b.hash()).collect();
+        assert_eq!(sync.request_blocks(peer(1)), hashes);
+    }
+
+    #[test]
+    fn pending_headers_are_capped() {
+        let remote = mined_chain(5);
+        let local = mined_chain(2);
+        let genesis = local.get_parent_block(local.genesis_hash()).unwrap();
+        let low = mine_at(&genesis, genesis.get_timestamp() + 2);
+
+        let mut sync = HeaderSync::with_limits(BLOCKS_PER_REQUEST, BLOCK_REQUEST_TIMEOUT, 2);
+        assert_eq!(sync.add_headers(&local, &[low.get_header()]), Ok(1));
+
+        // a low work branch makes room, but headers of the branch being downloaded stay
+        let headers = headers_after(&remote, &block_locator(&local.all_blocks_in_longest_chain()), H256::default());
+        assert_eq!(headers.len(), 3);
+        assert_eq!(sync.add_headers(&local, &headers), Ok(2));
+        assert_eq!(sync.len(), 2);
+        assert!(!sync.is_expected(&headers[2].hash()));
+        assert_eq!(sync.add_headers(&local, &headers), Ok(0));
+    }
+
+    #[test]
+    fn late_bodies_are_asked_again() {
+        let remote = mined_chain(2);
+        let local = blockchain();
+        let headers = headers_after(&remote, &block_locator(&local.all_blocks_in_longest_chain()), H256::default());
+
+        let mut sync = HeaderSync::with_limits(BLOCKS_PER_REQUEST, Duration::from_secs(0), MAX_PENDING_HEADERS);
+        sync.add_headers(&local, &headers).unwrap();
+        let first = sync.request_blocks(peer(1));
+        assert_eq!(first.len(), 2);
+
+        // peer 1 did not deliver in time
+        assert_eq!(sync.request_blocks(peer(2)), first);
+
+        // blocks that are in the blockchain are not asked for anymore
+        sync.remove(&first[0]);
+        assert_eq!(sync.request_blocks(peer(3)), first[1..].to_vec());
+
+        // a body that does not match its header is asked for again
+        let mut sync = HeaderSync::new();
+        sync.add_headers(&local, &headers).unwrap();
+        let first = sync.request_blocks(peer(1));
+        sync.block_received(&first[1]);
+        sync.retry(&first[1]);
+        assert_eq!(sync.request_blocks(peer(2)), first[1..].to_vec());
+    }
+}
@@ -1,20 +1,26 @@
+use super::ban::{Misbehavior, MAX_GET_BLOCKS};
 use super::message::Message;
-use super::peer;
+use super::orphan::OrphanPool;
+use super::sync::{self, HeaderSync};
+use super::peer::{self, PeerId};
 use super::server::Handle as ServerHandle;
 use crate::types::hash::{H256, Hashable};
 use std::sync::{Arc, Mutex};
 use crate::types::block::Block;
 use crate::blockchain::Blockchain;
 use crate::blockchain::Mempool;
-use std::collections::HashMap;
-use crate::types::transaction::{self, SignedTransaction};
-use crate::types::address::Address;
+use crate::blockchain::validation::{self, BlockError, TransactionError};
+use crate::types::transaction::SignedTransaction;
 
 use ring::signature::Ed25519KeyPair; 
 
 use log::{debug, warn, error};
 
 use std::thread;
+use std::time::Duration;
+
+/// How often bodies whose request timed out are asked from another peer
+const SYNC_TIMER_INTERVAL: Duration = Duration::from_secs(1);
 
 #[cfg(any(test,test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
@@ -27,6 +33,8 @@ pub struct Worker {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    orphans: Arc<Mutex<OrphanPool>>, // shared by all worker threads
+    sync: Arc<Mutex<HeaderSync>>, // shared by all worker threads
 }
 
 
@@ -44,6 +52,8 @@ impl Worker {
             server: server.clone(),
             blockchain: blockchain.clone(),
             mempool: mempool.clone(),
+            orphans: Arc::new(Mutex::new(OrphanPool::new())),
+            sync: Arc::new(Mutex::new(HeaderSync::new())),
         }
     }
 
@@ -56,11 +66,10 @@ impl Worker {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::spawn(move || self.sync_timer_loop());
     }
 
     fn worker_loop(&self) {
-        let mut orphan_buffer: HashMap<H256, Block> = HashMap::new();
-
         loop {
             let result = smol::block_on(self.msg_chan.recv());
             if let Err(e) = result {
@@ -69,7 +78,14 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Undecodable message from {}: {}", peer.addr(), e);
+                    self.server.report(peer.id(), Misbehavior::MalformedMessage);
+                    continue;
+                }
+            };
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -78,6 +94,18 @@ impl Worker {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
+                Message::Version { .. } | Message::VerAck => {
+                    // the server completes the handshake before passing on any message
+                    debug!("Unexpected handshake message from {}", peer.addr());
+                    self.server.report(peer.id(), Misbehavior::UnexpectedHandshake);
+                }
+                Message::GetAddr => {
+                    peer.write(Message::Addr(self.server.known_addresses()));
+                }
+                Message::Addr(addrs) => {
+                    debug!("Learned {} peer addresses from {}", addrs.len(), peer.addr());
+                    self.server.learn_addresses(&addrs);
+                }
                 Message::NewBlockHashes(nonce) => { // 
                     // let mut blocks_needed: Vec<H256> = Vec::new();
 
@@ -95,13 +123,17 @@ impl Worker {
                         // println!("{}", el.hash());
                     }
                 }
-                Message::GetBlocks(nonce) => {
+                Message::GetBlocks(mut nonce) => {
+                    if nonce.len() > MAX_GET_BLOCKS {
+                        self.server.report(peer.id(), Misbehavior::OversizedRequest);
+                        nonce.truncate(MAX_GET_BLOCKS);
+                    }
                     // push blocks in blockchain
                     for el in nonce {
                         let mut blocks_have: Vec<Block> = Vec::new();
 
-                        if self.blockchain.lock().unwrap().is_present(el) {
-                            blocks_have.push(self.blockchain.lock().unwrap().get_parent_block(el));
+                        if let Ok(block) = self.blockchain.lock().unwrap().get_parent_block(el) {
+                            blocks_have.push(block);
                         }
                         // println!("inside message::getBlocks");
                         // println!("{}", el.hash());
@@ -111,68 +143,104 @@ impl Worker {
                     }
                 }
                 Message::Blocks(nonce) => {
-                    // orphan buffer
-                    // key is parent of block, value is block
-
+                    let syncing = !self.sync.lock().unwrap().is_empty();
+                    let mut new_blocks: Vec<H256> = Vec::new();
                     for el in nonce {
-                        let mut new_blocks: Vec<H256> = Vec::new();
-                        let content_data = el.get_content();
-
-                        // check transaction in block valid
-                        let mut transaction_valid = true;
-                        for element in content_data {
-                            if !transaction::verify(&element.get_t(), &element.get_public_key(), &element.get_sig()) {
-                                transaction_valid = false;
-                                break;
-                            }
-                        }
+                        self.sync.lock().unwrap().block_received(&el.hash());
 
-                        if transaction_valid {
-                            if !{self.blockchain.lock().unwrap().is_present(el.hash())} {
-                                // PoW check
-                                if el.hash() <= el.get_difficulty() {
-                                    // parent check
-                                    if self.blockchain.lock().unwrap().is_present(el.get_parent()) {
-                                        // check difficulty in block header consistent with view
-                                        if el.get_difficulty() == {self.blockchain.lock().unwrap().get_parent_block(el.get_parent()).get_difficulty()} {
-                                            // insert into blockchain
-                                            {self.blockchain.lock().unwrap().insert(&el)};
-                                            // println!("inserted block -- network worker");
-                                            // println!("{}", el.hash());
-                                            // insert into vector of new blocks
-                                            new_blocks.push(el.hash());
-    
-                                            // orphan block handler
-                                            let mut count = el.clone();
-                                            while orphan_buffer.contains_key(&count.hash()) {
-                                                // process orphan block
-                                                let orphan = orphan_buffer.get(&count.hash()).unwrap();
-                                                {self.blockchain.lock().unwrap().insert(&orphan)};
-                                                new_blocks.push(orphan.hash());
-    
-                                                // update counter
-                                                count = orphan.clone();
-                                            }
-                                        } 
-                                    }
-                                    else {
-                                        // add block to orphan buffer
-                                        orphan_buffer.insert(el.get_parent(), el.clone());
-    
-                                        // send getBlocks message with parent hash
-                                        let mut to_send: Vec<H256> = Vec::new();
-                                        to_send.push(el.clone().get_parent().hash());
-                                        peer.write(Message::GetBlocks(to_send));
+                        match self.validate_and_insert(&el) {
+                            Ok(()) => {
+                                new_blocks.push(el.hash());
+                                new_blocks.extend(self.connect_orphans(el.hash()));
+                            }
+                            Err(BlockError::UnknownParent(_)) => {
+                                // keep the block until its parent arrives, and ask for the first
+                                // block missing below it unless header sync is already fetching it
+                                let mut orphans = self.orphans.lock().unwrap();
+                                let missing = orphans.missing_ancestor(&el);
+                                if orphans.insert(el.clone(), peer.id()) {
+                                    drop(orphans);
+                                    if !self.sync.lock().unwrap().is_expected(&missing) {
+                                        peer.write(Message::GetBlocks(vec![missing]));
                                     }
-                                    
+                                }
+                            }
+                            Err(BlockError::AlreadyKnown) => {}
+                            Err(e) if mismatches_header(&e) => {
+                                // the header may be fine with another body, so keep it and
+                                // ask again rather than blame a peer that only relayed it
+                                debug!("Body of block {} does not match its header: {}", el.hash(), e);
+                                self.sync.lock().unwrap().retry(&el.hash());
+                            }
+                            Err(e) => {
+                                debug!("Rejected block {}: {}", el.hash(), e);
+                                self.sync.lock().unwrap().remove(&el.hash());
+                                if breaks_consensus(&e) {
+                                    self.server.report(peer.id(), misbehavior_of(&e, Misbehavior::InvalidBlock));
                                 }
                             }
                         }
-                        
-                        // broadcast new blocks
-                        self.server.broadcast(Message::NewBlockHashes(new_blocks));
                     }
-                } 
+
+                    // announce the blocks that made it in to the other peers, all at once
+                    if !new_blocks.is_empty() {
+                        self.server.relay(Message::NewBlockHashes(new_blocks), peer.id());
+                    }
+
+                    // keep the peer busy with the next batch of a header sync, and ask for the
+                    // headers that follow once a sync cut off at its limit has all its bodies
+                    let (wanted, drained) = {
+                        let mut sync = self.sync.lock().unwrap();
+                        (sync.request_blocks(peer.id()), syncing && sync.is_empty())
+                    };
+                    if !wanted.is_empty() {
+                        peer.write(Message::GetBlocks(wanted));
+                    }
+                    if drained {
+                        peer.write(sync::get_headers(&self.blockchain.lock().unwrap()));
+                    }
+                }
+                Message::GetHeaders { locator, stop } => {
+                    let headers = sync::headers_after(&self.blockchain.lock().unwrap(), &locator, stop);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    let (result, more) = {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        let result = self.sync.lock().unwrap().add_headers(&blockchain, &headers);
+
+                        // a full reply means the peer has more, unless the sync is already full
+                        let more = match headers.last() {
+                            Some(last) if headers.len() >= sync::MAX_HEADERS && result.is_ok()
+                                && self.sync.lock().unwrap().is_expected(&last.hash()) => {
+                                Some(sync::get_more_headers(&blockchain, last.hash()))
+                            }
+                            _ => None,
+                        };
+                        (result, more)
+                    };
+                    let new_headers = matches!(result, Ok(added) if added > 0);
+                    match result {
+                        Ok(added) => debug!("Accepted {} new headers from {}", added, peer.addr()),
+                        Err(e) => {
+                            debug!("Rejected headers from {}: {}", peer.addr(), e);
+                            if breaks_consensus(&e) {
+                                self.server.report(peer.id(), misbehavior_of(&e, Misbehavior::InvalidHeaders));
+                            }
+                        }
+                    }
+                    if let Some(msg) = more {
+                        peer.write(msg);
+                    }
+
+                    let wanted = self.sync.lock().unwrap().request_blocks(peer.id());
+                    if !wanted.is_empty() {
+                        peer.write(Message::GetBlocks(wanted));
+                    }
+                    if new_headers {
+                        self.spread_block_requests(Some(peer.id()));
+                    }
+                }
                 Message::NewTransactionHashes(nonce) => {
                     // same as NewBlockHashes
                     for el in nonce {
@@ -189,49 +257,51 @@ impl Worker {
                     // same as GetBlocks
                     for el in nonce {
                         let mut transactions: Vec<SignedTransaction> = Vec::new();
-                        {
-                        let current_mempool = self.mempool.lock().unwrap();
-                        if current_mempool.is_present(el) {
-                            drop(current_mempool);
-                            transactions.push(self.mempool.lock().unwrap().get_transaction(el));
+                        let found = self.mempool.lock().unwrap().get_transaction(el);
+                        if let Ok(tx) = found {
+                            transactions.push(tx);
                             peer.write(Message::Transactions(transactions));
                             // println!("inside network get transactions");
                         }
-                        }
                     }
                 }
                 Message::Transactions(nonce) => {
                     // same as Blocks
                     for el in nonce {
-                        // check transaction signed correctly
-                        // println!("outside verify in transation worker");
-                        let transaction_verified = transaction::verify(&el.get_t(), &el.get_public_key(), &el.get_sig());
-                        // println!("{}", transaction_verified);
-                        if transaction_verified {
-                            // spending check
-                            let el_public_key = el.get_public_key();
-                            let address = Address::from_public_key_bytes(el_public_key.as_slice());
-                            // get state in block at tip of blockchain
-                            let tip_hash = self.blockchain.lock().unwrap().tip();
-                            let tip_block = self.blockchain.lock().unwrap().get_parent_block(tip_hash);
-                            let tip_state = tip_block.get_state();
-
-                            // check if sender key is in state
-                            if tip_state.contains_key(address) {
-                                // check if balance is enough
-                                let balance = tip_state.get(address).1;
-                                if balance >= el.get_t().get_value() {
-                                    // check suggested account nonce
-                                    if el.get_t().get_nonce() == (1 + tip_state.get(address).0) {
-                                        let mut transactions: Vec<H256> = Vec::new();
-
-                                        // if not in mempool, insert
-                                        {self.mempool.lock().unwrap().insert(el.hash(), &el);}
-
-                                        transactions.push(el.hash());
-                                        self.server.broadcast(Message::NewTransactionHashes(transactions));
-                                        // println!("inside network transactions");
-                                    }
+                        // get state in block at tip of blockchain
+                        let (tip_state, chain_id) = {
+                            let blockchain = self.blockchain.lock().unwrap();
+                            match blockchain.get_state(blockchain.tip()) {
+                                Ok(state) => (state, blockchain.chain_id()),
+                                Err(e) => {
+                                    warn!("Cannot check transaction {}: {}", el.hash(), e);
+                                    continue;
+                                }
+                            }
+                        };
+
+                        // check transaction signed correctly and passes the spending check after
+                        // the sender's pending transactions, so that back-to-back transfers of
+                        // one wallet are all accepted
+                        let state = {self.mempool.lock().unwrap().pending_state(&tip_state, el.get_t().get_sender())};
+                        match validation::validate_transaction(&state, &el, &chain_id) {
+                            Ok(()) => {
+                                // a block carrying it later does not verify it again
+                                {self.blockchain.lock().unwrap().signature_cache().insert(el.wtxid());}
+
+                                // announce it only if the mempool kept it, since peers asking for
+                                // it are served from the mempool
+                                let inserted = {self.mempool.lock().unwrap().insert(el.hash(), &el)};
+                                if inserted {
+                                    self.server.relay(Message::NewTransactionHashes(vec![el.hash()]), peer.id());
+                                }
+                            }
+                            Err(e) => {
+                                debug!("Rejected transaction {}: {}", el.hash(), e);
+                                // other errors can be a race with the tip, but a signature never
+                                // becomes valid
+                                if matches!(e, TransactionError::InvalidSignature | TransactionError::SenderMismatch { .. }) {
+                                    self.server.report(peer.id(), Misbehavior::InvalidSignature);
                                 }
                             }
                         }
@@ -240,6 +310,79 @@ impl Worker {
             }
         }
     }
+
+    // hand body requests that timed out to idle peers, even while no peer sends anything
+    fn sync_timer_loop(&self) {
+        loop {
+            thread::sleep(SYNC_TIMER_INTERVAL);
+            if !self.sync.lock().unwrap().is_empty() {
+                self.spread_block_requests(None);
+            }
+        }
+    }
+
+    // give every idle peer but the source a batch of the bodies a header sync is waiting for
+    fn spread_block_requests(&self, source: Option<PeerId>) {
+        for other in self.server.peers() {
+            if Some(other.id) == source {
+                continue;
+            }
+            let wanted = self.sync.lock().unwrap().request_blocks(other.id);
+            if !wanted.is_empty() {
+                self.server.send(other.id, Message::GetBlocks(wanted));
+            }
+        }
+    }
+
+    fn validate_and_insert(&self, block: &Block) -> Result<(), BlockError> {
+        let mut blockchain = self.blockchain.lock().unwrap();
+        validation::validate_block(&blockchain, block)?;
+        blockchain.insert(block);
+        drop(blockchain);
+        self.sync.lock().unwrap().remove(&block.hash());
+        Ok(())
+    }
+
+    // insert every orphan that descends from a newly inserted block, returning the inserted hashes
+    fn connect_orphans(&self, parent: H256) -> Vec<H256> {
+        let mut connected: Vec<H256> = Vec::new();
+        let mut parents: Vec<H256> = vec![parent];
+        while let Some(parent) = parents.pop() {
+            let children = self.orphans.lock().unwrap().take_children(&parent);
+            for orphan in children {
+                match self.validate_and_insert(&orphan) {
+                    Ok(()) => {
+                        connected.push(orphan.hash());
+                        parents.push(orphan.hash());
+                    }
+                    // descendants of a rejected orphan stay in the pool until they expire
+                    Err(e) => debug!("Rejected orphan block {}: {}", orphan.hash(), e),
+                }
+            }
+        }
+        connected
+    }
+}
+
+// whether a rejected block or header is invalid in itself, rather than unconnected, already known
+// or ahead of the local clock
+fn breaks_consensus(e: &BlockError) -> bool {
+    !matches!(e, BlockError::AlreadyKnown | BlockError::UnknownParent(_) | BlockError::TimestampTooNew { .. })
+}
+
+// what a rejected block or header that breaks consensus says about the peer; a difficulty target
+// depends on the retarget parameters, which are local configuration, so it scores lower
+fn misbehavior_of(e: &BlockError, otherwise: Misbehavior) -> Misbehavior {
+    match e {
+        BlockError::DifficultyMismatch { .. } => Misbehavior::UnexpectedDifficulty,
+        _ => otherwise,
+    }
+}
+
+// whether a rejected block has a body that its header does not commit to; the block hash covers
+// the header only, so the same hash can still arrive with the right body
+fn mismatches_header(e: &BlockError) -> bool {
+    matches!(e, BlockError::MerkleRootMismatch { .. } | BlockError::DuplicateTransaction { .. })
 }
 
 #[cfg(any(test,test_utilities))]
@@ -254,7 +397,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -291,6 +437,16 @@ mod test {
 
     use super::super::message::Message;
     use super::generate_test_worker_and_start;
+    use super::super::ban::{Misbehavior, MAX_GET_BLOCKS};
+    use super::super::peer::PeerId;
+    use crate::blockchain::Blockchain;
+    use crate::types::block::{self, Block};
+    use crate::types::hash::H256;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+    use crate::types::transaction::{self, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
 
     #[test]
     #[timeout(60000)]
@@ -332,6 +488,137 @@ mod test {
             panic!();
         }
     }
+
+    // empty block on top of parent with valid proof of work
+    fn mine_empty(parent: &Block) -> Block {
+        let merkle_root = MerkleTree::new(&Vec::<H256>::new()).root();
+        let mut nonce = 0;
+        loop {
+            let header = block::build_header(parent.hash(), nonce, parent.get_difficulty(), parent.get_timestamp() + 1, merkle_root, parent.get_state_root());
+            let new_block = block::build_block(header, block::build_content(vec![]));
+            if new_block.hash() <= new_block.get_difficulty() {
+                return new_block;
+            }
+            nonce += 1;
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphans_request_parent_and_connect() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let genesis = Blockchain::new(key_pair).get_parent_block(*v.last().unwrap()).unwrap();
+        let b1 = mine_empty(&genesis);
+        let b2 = mine_empty(&b1);
+        let b3 = mine_empty(&b2);
+
+        // b3 arrives first and asks for its parent itself
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![b3.clone()]));
+        match peer_receiver.recv() {
+            Message::GetBlocks(v) => assert_eq!(v, vec![b2.hash()]),
+            _ => panic!(),
+        }
+
+        // b2 is also an orphan, so the request goes further down
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![b2.clone()]));
+        match peer_receiver.recv() {
+            Message::GetBlocks(v) => assert_eq!(v, vec![b1.hash()]),
+            _ => panic!(),
+        }
+
+        // b1 connects the whole branch
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![b1.clone()]));
+        match server_receiver.recv().unwrap() {
+            Message::NewBlockHashes(v) => assert_eq!(v, vec![b1.hash(), b2.hash(), b3.hash()]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn headers_are_followed_by_body_requests() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let genesis = Blockchain::new(key_pair).get_parent_block(*v.last().unwrap()).unwrap();
+        let b1 = mine_empty(&genesis);
+        let b2 = mine_empty(&b1);
+
+        let mut peer_receiver = test_msg_sender.send(Message::Headers(vec![b1.get_header(), b2.get_header()]));
+        match peer_receiver.recv() {
+            Message::GetBlocks(v) => assert_eq!(v, vec![b1.hash(), b2.hash()]),
+            _ => panic!(),
+        }
+
+        // and headers are served from the longest chain
+        let mut peer_receiver = test_msg_sender.send(Message::GetHeaders { locator: v.clone(), stop: H256::default() });
+        match peer_receiver.recv() {
+            Message::Headers(headers) => assert!(headers.is_empty()),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn misbehavior_is_reported() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let _peer_receiver = test_msg_sender.send_bytes(vec![0xff; 3]);
+        let (addr, misbehavior) = server_receiver.recv_report().unwrap();
+        assert_eq!(misbehavior, Misbehavior::MalformedMessage);
+
+        // an oversized request is still answered, up to the limit
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![v[0]; MAX_GET_BLOCKS + 1]));
+        assert_eq!(server_receiver.recv_report(), Some((addr, Misbehavior::OversizedRequest)));
+        match peer_receiver.recv() {
+            Message::Blocks(blocks) => assert_eq!(blocks.len(), 1),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn new_blocks_are_not_relayed_back() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let genesis = Blockchain::new(key_pair).get_parent_block(*v.last().unwrap()).unwrap();
+        let b1 = mine_empty(&genesis);
+        let b2 = mine_empty(&b1);
+
+        // the blocks of one message are announced together, known ones not again
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![b1.clone(), b1.clone(), b2.clone()]));
+        match server_receiver.recv_relay() {
+            Some((Message::NewBlockHashes(v), except)) => {
+                assert_eq!(v, vec![b1.hash(), b2.hash()]);
+                assert_eq!(except, Some(PeerId(0)));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn consecutive_transactions_are_accepted() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let blockchain = Blockchain::new(Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap());
+        let sender = Address::from_public_key_bytes(key_pair.public_key().as_ref());
+        let (nonce, _) = blockchain.get_state(blockchain.tip()).unwrap().get(sender).unwrap();
+        let signed = |nonce: u64| {
+            let t = Transaction::new(sender, [7u8; 20].into(), Amount::new(1), Amount::new(0), nonce);
+            let sig = transaction::sign(&t, &blockchain.chain_id(), &key_pair).as_ref().to_vec();
+            SignedTransaction::new(t, sig, key_pair.public_key().as_ref().to_vec())
+        };
+        let (t1, t2) = (signed(nonce + 1), signed(nonce + 2));
+
+        // the second one follows the first in the mempool rather than the tip
+        let _peer_receiver = test_msg_sender.send(Message::Transactions(vec![t1.clone(), t2.clone()]));
+        for tx in [t1, t2] {
+            match server_receiver.recv_relay() {
+                Some((Message::NewTransactionHashes(hashes), _)) => assert_eq!(hashes, vec![tx.hash()]),
+                _ => panic!(),
+            }
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,24 +1,26 @@
 pub mod worker;
 
-use log::info;
+use log::{info, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time::Duration;
-use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
-use crate::types::transaction;
+use ring::signature::{self, UnparsedPublicKey, Signature, VerificationAlgorithm, EdDSAParameters};
 
 use std::thread;
 use std::sync::{Arc, Mutex};
 
 use crate::blockchain::Mempool;
 use crate::blockchain::Blockchain;
-use crate::types::address::Address;
+use crate::types::amount::Amount;
 use super::types::hash::Hashable;
-use super::types::transaction::{Transaction, SignedTransaction};
-use crate::types::key_pair;
+use super::types::transaction::SignedTransaction;
+use crate::wallet::Wallet;
 
 use rand::Rng;
 
+/// Most accounts the generator creates in the wallet
+pub const MAX_GENERATED_ACCOUNTS: usize = 32;
+
 enum ControlSignal {
     Start(u64), // the number controls the theta of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
@@ -38,7 +40,7 @@ pub struct Context {
     finished_transaction_chan: Sender<SignedTransaction>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
-    accounts: Vec<Ed25519KeyPair>, // key is key_pair, value is (value, nonce)
+    wallet: Arc<Mutex<Wallet>>, // accounts that send and receive the generated transactions
 }
 
 #[derive(Clone)]
@@ -63,22 +65,19 @@ impl Handle {
     }
 }
 
-pub fn new(mempool: Arc<Mutex<Mempool>>, key_pair: Ed25519KeyPair, blockchain: Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<SignedTransaction>) {
+pub fn new(mempool: Arc<Mutex<Mempool>>, wallet: Arc<Mutex<Wallet>>, blockchain: Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_tx_sender, finished_tx_receiver) = unbounded();
 
     let clone_mempool = Arc::clone(&mempool);
 
-    let mut accounts: Vec<Ed25519KeyPair> = Vec::new();
-    accounts.push(key_pair);
-
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_transaction_chan: finished_tx_sender,
         blockchain,
         mempool: clone_mempool,
-        accounts,
+        wallet,
     };
 
     let handle = Handle {
@@ -136,7 +135,7 @@ impl Context {
                                 self.operating_state = OperatingState::Run(i);
                             }
                             ControlSignal::Update => {
-                                unimplemented!()
+                                // the tip state is read again on every iteration below
                             }
                         };
                     }
@@ -153,41 +152,36 @@ impl Context {
             let prob = prob_rng.gen_range(0.0..1.0);
 
             if prob < 0.1 {
-                let prob_receiver_key_pair = key_pair::random();
-                self.accounts.push(prob_receiver_key_pair); // ASK ABOUT THIS
+                let mut wallet = self.wallet.lock().unwrap();
+                if wallet.len() < MAX_GENERATED_ACCOUNTS {
+                    if let Err(e) = wallet.new_address() {
+                        warn!("Cannot create account: {}", e);
+                    }
+                }
             }
 
             // generate signed transaction
 
             // FOR SENDER
-            for sender in &self.accounts {
-                // println!("self.accounts not empty");
-                let tip_hash = {self.blockchain.lock().unwrap().tip()};
-                let tip_block = {self.blockchain.lock().unwrap().get_parent_block(tip_hash)};
-                let state = tip_block.get_state();
-                //println!("state empty?");
-                // println!("{}", state.get_state().is_empty());
-
-                // println!("state contains initial key pair?");
-                // println!("{}", state.get_state().contains_key(&Address::from_public_key_bytes(self.accounts[0].public_key().as_ref())));
-
-                let sender_address = Address::from_public_key_bytes(sender.public_key().as_ref());
-
-                // println!("state contains sender_address?");
-                // println!("{}", state.contains_key(sender_address));
+            let accounts = self.wallet.lock().unwrap().addresses();
+            for &sender_address in &accounts {
+                let (state, chain_id) = {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    match blockchain.get_state(blockchain.tip()) {
+                        Ok(state) => (state, blockchain.chain_id()),
+                        Err(e) => {
+                            warn!("Cannot generate transaction: {}", e);
+                            break;
+                        }
+                    }
+                };
 
-                if state.contains_key(sender_address) {
-                    // println!("state contains key");
+                if let Some((_, sender_balance)) = state.get(sender_address) {
                     let mut rng = rand::thread_rng();
-                
-                    let sender_nonce = state.get(sender_address.clone()).0;
-                    let sender_balance = state.get(sender_address.clone()).1;
+                    let sender_balance = sender_balance.as_u64();
 
-                    // set value and nonce of signed transaction
-                    let value: u32;
-
-                    // println!("sender_balance");
-                    // println!("{}", sender_balance);
+                    // set value of signed transaction
+                    let value: u64;
 
                     if sender_balance > 1 {
                         if sender_balance > 20 {
@@ -201,27 +195,29 @@ impl Context {
                         value = 0;
                     }
                     
-                    let nonce = sender_nonce + 1;
+                    // a small fee out of what is left
+                    let fee = rng.gen_range(0..=std::cmp::min(sender_balance - value, 10));
 
                     // FOR RECEIVER
 
-                    // generate receiver address
-                    // if prob is less than 0.1, create another account
-                    let receiver_index = rng.gen_range(0..self.accounts.len());
-                    let receiver_key_pair = self.accounts.get(receiver_index).unwrap();
-                    let receiver = Address::from_public_key_bytes(receiver_key_pair.public_key().as_ref());
-
-                    let transaction = Transaction::new(receiver, value, nonce);
-                    let sig = transaction::sign(&transaction, &sender).as_ref().to_vec();
-                    let signed_transaction = SignedTransaction::new(transaction, sig, sender.public_key().as_ref().to_vec());
-                    
-                    // println!("transaction generated in txgen mod");
+                    // pick the receiver among the wallet's accounts
+                    let receiver = accounts[rng.gen_range(0..accounts.len())];
+
+                    // the wallet picks the next nonce and signs
+                    let pending = self.mempool.lock().unwrap().pending_state(&state, sender_address);
+                    let transfer = self.wallet.lock().unwrap().transfer(&pending, &chain_id, sender_address, receiver, Amount::new(value), Amount::new(fee));
+                    let signed_transaction = match transfer {
+                        Ok(signed_transaction) => signed_transaction,
+                        Err(e) => {
+                            warn!("Cannot generate transaction: {}", e);
+                            continue;
+                        }
+                    };
             
                     self.finished_transaction_chan.send(signed_transaction.clone()).expect("Send finished block error");
-                    // put into mempool
+                    // put into mempool; signed here, so its witness is known to verify
                     {self.mempool.lock().unwrap().insert(signed_transaction.hash(), &signed_transaction)};
-
-                    // println!("after insert into mempool");
+                    self.blockchain.lock().unwrap().signature_cache().insert(signed_transaction.wtxid());
                 }
                 
                 
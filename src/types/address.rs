@@ -1,11 +1,18 @@
 use serde::{Serialize, Deserialize};
 use ring::digest;
 use std::convert::TryInto;
+use super::encoding;
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct Address([u8; 20]);
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::convert::From<&[u8; 20]> for Address {
     fn from(input: &[u8; 20]) -> Address {
         let mut buffer: [u8; 20] = [0; 20];
@@ -38,6 +45,17 @@ impl std::fmt::Display for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = hex::FromHexError;
+
+    // parse the 40 hex digit form printed by Display
+    fn from_str(s: &str) -> Result<Address, Self::Err> {
+        let mut buffer: [u8; 20] = [0; 20];
+        hex::decode_to_slice(s, &mut buffer)?;
+        Ok(Address(buffer))
+    }
+}
+
 impl std::fmt::Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -65,6 +83,13 @@ impl Address {
         // last 20 digits of hash (as array)
         Address(hash_20[..].try_into().unwrap())
     }
+
+    /// Address of an m-of-n multisig account, which does not depend on the order of the keys
+    pub fn from_multisig(threshold: u32, public_keys: &[Vec<u8>]) -> Address {
+        let mut keys = public_keys.to_vec();
+        keys.sort();
+        Address::from_public_key_bytes(&encoding::encode(&("multisig", threshold, keys)))
+    }
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
@@ -0,0 +1,74 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// An amount of coins: account balances, transferred values, fees and block rewards.
+///
+/// Arithmetic is only available in checked form, so an overflow or underflow surfaces as a
+/// validation error instead of wrapping or panicking.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Copy, Hash, Default, Debug)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn new(value: u64) -> Amount {
+        Amount(value)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// Add, returning `None` on overflow
+    pub fn checked_add(&self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    /// Subtract, returning `None` on underflow
+    pub fn checked_sub(&self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(value: u64) -> Amount {
+        Amount(value)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::str::FromStr for Amount {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Amount, Self::Err> {
+        s.parse::<u64>().map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic() {
+        let a = Amount::new(40);
+        assert_eq!(a.checked_add(Amount::new(2)), Some(Amount::new(42)));
+        assert_eq!(a.checked_sub(Amount::new(40)), Some(Amount::ZERO));
+        assert_eq!(a.checked_sub(Amount::new(41)), None);
+        assert_eq!(Amount::MAX.checked_add(Amount::new(1)), None);
+    }
+
+    #[test]
+    fn serializes_as_a_number() {
+        let a = Amount::new(5_000_000_000);
+        assert_eq!(serde_json::to_string(&a).unwrap(), "5000000000");
+        assert_eq!(bincode::serialize(&a).unwrap(), 5_000_000_000u64.to_le_bytes().to_vec());
+        assert_eq!("5000000000".parse::<Amount>().unwrap(), a);
+    }
+}
@@ -1,15 +1,18 @@
 use serde::{Serialize, Deserialize};
-use ring::digest;
 use crate::types::hash::{H256, Hashable};
 use super::transaction;
 use std::collections::HashMap;
 use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::encoding;
+use crate::types::merkle::{self, MerkleTree};
 
+// the post-state of a block is not part of the block; every node derives it by replaying the
+// content on top of the parent's state (see Blockchain::insert)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     header: Header, // see Header struct below
     data: Content, // see Content struct below
-    state: State, // see State struct below
 }
 
 // MY CODE
@@ -19,7 +22,8 @@ pub struct Header {
     nonce: u32, // random integer used in proof-of-work mining
     difficulty: H256, // threshold in proof-of-work check
     timestamp: u128, // timestamp when block generated
-    merkle_root: H256, // Merkle root of data
+    merkle_root: H256, // Merkle root of the wtxids of data, see merkle_root
+    state_root: H256, // Merkle root of the state after applying data, see State::root
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -28,20 +32,20 @@ pub struct Content {
 }
 
 // MY CODE
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct State {
     // stores <address, (account nonce, account balance)>
-    state: HashMap<Address, (u32, u32)>,
+    state: HashMap<Address, (u64, Amount)>,
 }
 
 impl State {
     pub fn new() -> Self {
-        let state: HashMap<Address, (u32, u32)> = HashMap::new();
+        let state: HashMap<Address, (u64, Amount)> = HashMap::new();
         State{state}
     }
 
     // insert account into state
-    pub fn insert(&mut self, address: Address, nonce: u32, balance: u32) {
+    pub fn insert(&mut self, address: Address, nonce: u64, balance: Amount) {
         self.state.insert(address, (nonce, balance));
     }
 
@@ -50,22 +54,76 @@ impl State {
         self.state.contains_key(&address)
     }
 
-    // get nonce and balance
-    pub fn get(&self, address: Address) -> (u32, u32) {
-        self.state.get(&address).unwrap().clone()
+    // get nonce and balance, if the account exists
+    pub fn get(&self, address: Address) -> Option<(u64, Amount)> {
+        self.state.get(&address).copied()
     }
 
     // get content
-    pub fn get_state(&self) -> HashMap<Address, (u32, u32)> {
+    pub fn get_state(&self) -> HashMap<Address, (u64, Amount)> {
         self.state.clone()
     }
+
+    // accounts sorted by address, which are the leaves of the state tree
+    fn sorted_accounts(&self) -> Vec<Account> {
+        let mut accounts: Vec<Account> = self.state.iter()
+            .map(|(address, (nonce, balance))| Account { address: *address, nonce: *nonce, balance: *balance })
+            .collect();
+        accounts.sort_by_key(|account| account.address);
+        accounts
+    }
+
+    /// Root of a Merkle tree over every account sorted by address, committed to in the header
+    pub fn root(&self) -> H256 {
+        MerkleTree::new(&self.sorted_accounts()).root()
+    }
+
+    /// Inclusion proof of an account against the state root, if the account exists
+    pub fn proof(&self, address: Address) -> Option<StateProof> {
+        let accounts = self.sorted_accounts();
+        let index = accounts.binary_search_by_key(&address, |account| account.address).ok()?;
+        let siblings = MerkleTree::new(&accounts).proof(index);
+        Some(StateProof { account: accounts[index].clone(), index, leaf_count: accounts.len(), siblings })
+    }
+}
+
+/// An account as a leaf of the state tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub address: Address,
+    pub nonce: u64,
+    pub balance: Amount,
+}
+
+impl Hashable for Account {
+    fn hash(&self) -> H256 {
+        encoding::hash(self)
+    }
+}
+
+/// Proof that an account is in the state committed to by a header's state root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateProof {
+    pub account: Account,
+    pub index: usize, // position of the account among all accounts sorted by address
+    pub leaf_count: usize, // number of accounts in the state
+    pub siblings: Vec<H256>, // Merkle proof of the account leaf
+}
+
+impl StateProof {
+    /// Verify the proof against a state root taken from a header
+    pub fn verify(&self, state_root: &H256) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        merkle::verify(state_root, &self.account.hash(), &self.siblings, self.index, self.leaf_count)
+    }
 }
 
 // MY CODE
 impl Hashable for Header {
     fn hash(&self) -> H256 {
-        let serialized = serde_json::to_string(&self).unwrap();
-        digest::digest(&digest::SHA256, serialized.as_ref()).into()
+        encoding::hash(self)
     }
 }
 
@@ -76,7 +134,29 @@ impl Hashable for Block {
     }
 }
 
+impl Header {
+    // return parent
+    pub fn get_parent(&self) -> H256 {
+        self.parent
+    }
+
+    // return difficulty
+    pub fn get_difficulty(&self) -> H256 {
+        self.difficulty
+    }
+
+    // return timestamp
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+}
+
 impl Block {
+    // return header
+    pub fn get_header(&self) -> Header {
+        self.header.clone()
+    }
+
     // return parent
     pub fn get_parent(&self) -> H256 {
         // MY CODE
@@ -89,17 +169,28 @@ impl Block {
         self.header.difficulty.clone()
     }
 
+    // return timestamp
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    // return merkle root
+    pub fn get_merkle_root(&self) -> H256 {
+        self.header.merkle_root
+    }
+
+    // return state root
+    pub fn get_state_root(&self) -> H256 {
+        self.header.state_root
+    }
+
     // MY CODE
     // return content
     pub fn get_content(&self) -> Vec<transaction::SignedTransaction> {
         self.data.content_data.clone()
     }
 
-    pub fn get_state(&self) -> State {
-        self.state.clone()
-    }
-
-    // return hashed content as vector of string
+    // return txids of content
     pub fn get_hashed_content(&self) -> Vec<H256> {
         let vector = self.data.content_data.clone();
 
@@ -115,11 +206,6 @@ impl Block {
     pub fn insert_transaction(&mut self, tx: transaction::SignedTransaction) {
         self.data.content_data.push(tx);
     }
-
-    // update the state
-    pub fn put_state(&mut self, state: State) {
-        self.state = state;
-    }
 }
 
 // MY CODE
@@ -144,12 +230,19 @@ pub fn build_content(content_data: Vec<transaction::SignedTransaction>) -> Conte
     Content{ content_data }
 }
 
-pub fn build_header(parent: H256, nonce: u32, difficulty: H256, timestamp: u128, merkle_root: H256) -> Header {
-    Header{ parent, nonce, difficulty, timestamp, merkle_root }
+/// Merkle root committed to in the header: over the wtxids of the transactions, so that the
+/// header also commits to their signatures
+pub fn merkle_root(content_data: &[transaction::SignedTransaction]) -> H256 {
+    let wtxids: Vec<H256> = content_data.iter().map(|tx| tx.wtxid()).collect();
+    MerkleTree::new(&wtxids).root()
+}
+
+pub fn build_header(parent: H256, nonce: u32, difficulty: H256, timestamp: u128, merkle_root: H256, state_root: H256) -> Header {
+    Header{ parent, nonce, difficulty, timestamp, merkle_root, state_root }
 }
 
-pub fn build_block(header: Header, data: Content, state: State) -> Block {
-     Block{ header, data, state }
+pub fn build_block(header: Header, data: Content) -> Block {
+     Block{ header, data }
 }
 
 #[cfg(any(test, test_utilities))] // WHAT DOES THIS DO??
@@ -162,13 +255,52 @@ pub fn generate_random_block(parent: &H256) -> Block {
     let timestamp = 0;
     let content_data: Vec<transaction::SignedTransaction> = Vec::new();
     let data: Content = Content{ content_data };
-    let state: State = State::new();
 
     // merkle root of empty input
     // FOR NOW, BUT NEED TO IMPLEMENT IN MERKLE.RS
     let merkle_root: H256 = H256::from(zeros); 
+    let state_root: H256 = H256::from(zeros);
 
-    let header: Header = Header{ parent, nonce, difficulty, timestamp, merkle_root };
+    let header: Header = Header{ parent, nonce, difficulty, timestamp, merkle_root, state_root };
     
-    Block{ header, data, state }
+    Block{ header, data }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_root_is_order_independent() {
+        let mut state_1 = State::new();
+        let mut state_2 = State::new();
+        for i in 0..5u8 {
+            state_1.insert([i; 20].into(), i as u64, Amount::new(100 * i as u64));
+            state_2.insert([4 - i; 20].into(), (4 - i) as u64, Amount::new(100 * (4 - i) as u64));
+        }
+        assert_eq!(state_1.root(), state_2.root());
+
+        state_2.insert([0; 20].into(), 0, Amount::new(1));
+        assert_ne!(state_1.root(), state_2.root());
+    }
+
+    #[test]
+    fn state_proof_verifies_against_root() {
+        let mut state = State::new();
+        for i in 0..5u8 {
+            state.insert([i; 20].into(), i as u64, Amount::new(100 * i as u64));
+        }
+        let root = state.root();
+        for i in 0..5u8 {
+            let proof = state.proof([i; 20].into()).unwrap();
+            assert_eq!(proof.account.balance, Amount::new(100 * i as u64));
+            assert!(proof.verify(&root));
+
+            // a forged balance does not verify
+            let mut forged = proof.clone();
+            forged.account.balance = Amount::new(forged.account.balance.as_u64() + 1);
+            assert!(!forged.verify(&root));
+        }
+        assert!(state.proof([9; 20].into()).is_none());
+    }
 }
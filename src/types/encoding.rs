@@ -0,0 +1,24 @@
+use serde::Serialize;
+use ring::digest;
+use super::hash::H256;
+
+/// Version of the consensus encoding, written as the first byte of every encoding so that a
+/// future layout change cannot collide with hashes or signatures made under this one.
+pub const VERSION: u8 = 2;
+
+/// Deterministic binary encoding used for every consensus hash and signature.
+///
+/// After the version byte comes bincode's default layout: fields in declaration order, integers
+/// little endian with a fixed width, sequences prefixed with their length as a u64. Reordering or
+/// retyping a field of a hashed struct changes the encoding, which the golden vectors in
+/// `types::hash` catch.
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![VERSION];
+    bincode::serialize_into(&mut bytes, value).expect("consensus types always serialize");
+    bytes
+}
+
+/// SHA256 of the consensus encoding of a value
+pub fn hash<T: Serialize>(value: &T) -> H256 {
+    digest::digest(&digest::SHA256, &encode(value)).into()
+}
@@ -116,4 +116,47 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::amount::Amount;
+    use crate::types::block;
+    use crate::types::encoding;
+    use crate::types::transaction::{self, SignedTransaction, Transaction};
+
+    // golden vectors: these must only change together with encoding::VERSION
+
+    fn golden_transaction() -> Transaction {
+        Transaction::new([1; 20].into(), [2; 20].into(), Amount::new(1000), Amount::new(7), 3)
+    }
+
+    #[test]
+    fn transaction_encoding_is_stable() {
+        let tx = SignedTransaction::new(golden_transaction(), vec![4; 64], vec![5; 32]);
+        assert_eq!(hex::encode(encoding::encode(&tx)), "0201010101010101010101010101010101010101010202020202020202020202020202020202020202e803000000000000070000000000000003000000000000000000000040000000000000000404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040404040420000000000000000505050505050505050505050505050505050505050505050505050505050505");
+        assert_eq!(tx.txid().to_string(), "4b8804411885a19723ec24359164313852698d430176be97dfdc5f620e16cb86");
+        assert_eq!(tx.wtxid().to_string(), "f71782b838f3cc88f98b2ba80a3a4f15a79229b0b107b7f6ec03f2965536b6ef");
+        assert_eq!(hex::encode(transaction::signed_payload(&golden_transaction(), &H256::from([6; 32]))), "02060606060606060606060606060606060606060606060606060606060606060601010101010101010101010101010101010101010202020202020202020202020202020202020202e80300000000000007000000000000000300000000000000");
+    }
+
+    #[test]
+    fn header_encoding_is_stable() {
+        let header = block::build_header(H256::from([1; 32]), 7, H256::from([2; 32]), 1_600_000_000_000, H256::from([3; 32]), H256::from([4; 32]));
+        assert_eq!(hex::encode(encoding::encode(&header)), "02010101010101010101010101010101010101010101010101010101010101010107000000020202020202020202020202020202020202020202020202020202020202020200806e8774010000000000000000000003030303030303030303030303030303030303030303030303030303030303030404040404040404040404040404040404040404040404040404040404040404");
+        assert_eq!(header.hash().to_string(), "9222fda181ee7af0bfbab1873b053143d4b7c42a89408e2a6794978f626b4f46");
+    }
+
+    #[test]
+    fn account_encoding_is_stable() {
+        let account = block::Account { address: [1; 20].into(), nonce: 3, balance: Amount::new(1000) };
+        assert_eq!(hex::encode(encoding::encode(&account)), "0201010101010101010101010101010101010101010300000000000000e803000000000000");
+        assert_eq!(account.hash().to_string(), "d396c7460deb5f71038c6e1e2457795d8a1d613ef46c1f68e9793165cef48a01");
+
+        // the state root commits to these leaves
+        let mut state = block::State::new();
+        state.insert([1; 20].into(), 3, Amount::new(1000));
+        state.insert([2; 20].into(), 0, Amount::new(5));
+        assert_eq!(state.root().to_string(), "4391c0fc7b81aa330657f0ffa51dc31e9c421ee5d9f0b1db2cc2149b9cd9f079");
+    }
+}
@@ -78,24 +78,23 @@ impl MerkleTree {
         let mut proof: Vec<H256> = Vec::new();
 
         // counters
-        let mut current_index: usize = 0; // current index of loop
-        let mut current_row: usize = 0; // current row of loop
+        let mut current_index: usize = 0; // index in tree where current row starts
         let mut new_index: usize = index; // index on current row
 
-        while current_index < self.tree.len() - 1 {
+        // every row except the root has a sibling to add to the proof
+        for row_length in self.tree_size.iter() {
             if new_index % 2 == 0 {
                 // if even, then sibling is entry on right
-                proof.push(H256::from(self.tree[(current_index + index + 1) as usize]));
+                proof.push(H256::from(self.tree[current_index + new_index + 1]));
             }
             else {
                 // if odd, then sibling is entry on left
-                proof.push(H256::from(self.tree[(current_index + index - 1) as usize]));
+                proof.push(H256::from(self.tree[current_index + new_index - 1]));
             }
 
             // updates counters
-            current_index = current_index + self.tree_size[current_row];
-            new_index = index / 2; // integer division
-            current_row = current_row + 1;
+            current_index += row_length;
+            new_index /= 2; // integer division
         }
 
         proof
@@ -196,6 +195,19 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn merkle_verifying_deep_tree() {
+        // five leaves pad to rows of 6, 4 and 2
+        let input_data: Vec<H256> = (0..5u8).map(|i| [i; 32].into()).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        for index in 0..input_data.len() {
+            let proof = merkle_tree.proof(index);
+            assert_eq!(proof.len(), 3);
+            assert!(verify(&merkle_tree.root(), &input_data[index].hash(), &proof, index, input_data.len()));
+            assert!(!verify(&merkle_tree.root(), &input_data[(index + 1) % 5].hash(), &proof, index, input_data.len()));
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,6 +1,9 @@
 pub mod address;
+pub mod amount;
 pub mod block;
+pub mod encoding;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
-pub mod transaction;
\ No newline at end of file
+pub mod transaction;
+pub mod uint;
\ No newline at end of file
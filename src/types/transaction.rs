@@ -1,9 +1,11 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use rand::Rng;
-use ring::digest;
+use rayon::prelude::*;
 use std::convert::TryInto;
 use super::address::Address;
+use super::amount::Amount;
+use super::encoding;
 use crate::types::hash::H256;
 use crate::types::hash::Hashable;
 
@@ -12,47 +14,89 @@ pub struct Transaction {
     // MY CODE
 
     // account-based model transaction
+    sender: Address, // must match the key or multisig account that signs the transaction
     receiver: Address,
-    value: u32, // make big for now
-    nonce: u32, 
+    value: Amount,
+    fee: Amount, // paid by the sender on top of value, orders the mempool
+    nonce: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+/// Most keys in a multisig account
+pub const MAX_MULTISIG_KEYS: usize = 16;
+/// Fewest transactions `verify_witnesses` spreads over the thread pool
+pub const PARALLEL_BATCH_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SignedTransaction {
     // MY CODE
 
     t: Transaction,
-    sig: Vec<u8>,
-    public_key: Vec<u8>,
+    witness: Witness,
+}
+
+/// What authorizes a transaction to spend from its sender account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Witness {
+    /// A signature by the single key the sender address is derived from
+    Single { sig: Vec<u8>, public_key: Vec<u8> },
+    /// Signatures by at least `threshold` distinct keys of an m-of-n account, given as
+    /// (public key, signature) pairs, along with the whole key set the address is derived from
+    Multi { threshold: u32, public_keys: Vec<Vec<u8>>, sigs: Vec<(Vec<u8>, Vec<u8>)> },
+    /// Nothing: the block reward has no sender
+    Coinbase,
+}
+
+impl Witness {
+    /// Address of the account this witness can spend from, none for a coinbase
+    pub fn address(&self) -> Option<Address> {
+        match self {
+            Witness::Single { public_key, .. } => Some(Address::from_public_key_bytes(public_key)),
+            Witness::Multi { threshold, public_keys, .. } => Some(Address::from_multisig(*threshold, public_keys)),
+            Witness::Coinbase => None,
+        }
+    }
 }
 
 impl Transaction {
     // new function
-    pub fn new(receiver: Address, value: u32, nonce: u32)-> Transaction {
-        Transaction {receiver, value, nonce}
+    pub fn new(sender: Address, receiver: Address, value: Amount, fee: Amount, nonce: u64)-> Transaction {
+        Transaction {sender, receiver, value, fee, nonce}
     }
 
-    pub fn get_value(&self) -> u32 {
-        self.value.clone()
+    pub fn get_value(&self) -> Amount {
+        self.value
     }
 
-    pub fn get_nonce(&self) -> u32 {
-        self.nonce.clone()
+    pub fn get_fee(&self) -> Amount {
+        self.fee
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn get_sender(&self) -> Address {
+        self.sender
     }
 
     pub fn get_receiver(&self) -> Address {
         self.receiver.clone()
     }
+
+    /// Id of the transaction: hash of the unsigned transaction
+    pub fn txid(&self) -> H256 {
+        encoding::hash(self)
+    }
 }
 
+// a signed transaction is identified by its txid, so that re-signing it does not make another
+// transaction
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         // MY CODE
         // Miraculously compiled?
 
-        // serialize transaction as slice of bytes
-        let serialized = serde_json::to_string(&self).unwrap(); // &[u8]
-        digest::digest(&digest::SHA256, serialized.as_ref()).into()
+        self.txid()
 
         // let serialized = serde_json::to_string(&self.t).unwrap().as_ref();
         // let hash: [u8] = digest::digest(&digest::SHA256, serialized).as_ref();
@@ -64,57 +108,141 @@ impl Hashable for SignedTransaction {
 impl SignedTransaction {
     // new function
     pub fn new(t: Transaction, sig: Vec<u8>, public_key: Vec<u8>) -> SignedTransaction {
-        SignedTransaction {t, sig, public_key}
+        SignedTransaction {t, witness: Witness::Single {sig, public_key}}
+    }
+
+    /// Create a transaction spending from an m-of-n account, with (public key, signature) pairs
+    /// of the keys that signed it
+    pub fn new_multisig(t: Transaction, threshold: u32, public_keys: Vec<Vec<u8>>, sigs: Vec<(Vec<u8>, Vec<u8>)>) -> SignedTransaction {
+        SignedTransaction {t, witness: Witness::Multi {threshold, public_keys, sigs}}
+    }
+
+    /// Create the coinbase transaction of a block, paying the block reward and fees to `receiver`.
+    ///
+    /// It has no sender, so a zero sender address and no witness; its nonce is the block height,
+    /// which makes every coinbase distinct.
+    pub fn coinbase(receiver: Address, value: Amount, height: u64) -> SignedTransaction {
+        let t = Transaction::new(Address::default(), receiver, value, Amount::ZERO, height);
+        SignedTransaction {t, witness: Witness::Coinbase}
+    }
+
+    /// Id of the transaction, which does not cover the witness
+    pub fn txid(&self) -> H256 {
+        self.t.txid()
+    }
+
+    /// Hash of the whole signed transaction, including the witness
+    pub fn wtxid(&self) -> H256 {
+        encoding::hash(self)
+    }
+
+    // check if this is a coinbase transaction
+    pub fn is_coinbase(&self) -> bool {
+        self.witness == Witness::Coinbase
     }
 
     // get transaction
     pub fn get_t(&self) -> Transaction {
         self.t.clone()
     }
-    // get public key
-    pub fn get_public_key(&self) -> Vec<u8> {
-        self.public_key.clone()
-    }
-    // get signature
-    pub fn get_sig(&self) -> Vec<u8> {
-        self.sig.clone()
+    // get witness
+    pub fn get_witness(&self) -> &Witness {
+        &self.witness
     }
 }
 
-/// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+/// Create digital signature of a transaction, valid only on the chain identified by `chain_id`
+/// (the hash of its genesis block)
+pub fn sign(t: &Transaction, chain_id: &H256, key: &Ed25519KeyPair) -> Signature {
     // MY CODE
 
     // serialize transaction as slice of bytes
-    let serialized = serde_json::to_string(&t).unwrap();
+    let serialized = signed_payload(t, chain_id);
 
     // sign with key
-    key.sign(serialized.as_bytes())
+    key.sign(&serialized)
 }
 
-/// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
+/// Verify digital signature of a transaction on the chain identified by `chain_id`, using public
+/// key instead of secret key
+pub fn verify(t: &Transaction, chain_id: &H256, public_key: &[u8], signature: &[u8]) -> bool {
     // MY CODE
 
     // makes unparsed public key object
     let peer_public_key = UnparsedPublicKey::new(&signature::ED25519, public_key);
     // serializes transaction
-    let serialized = serde_json::to_string(&t).unwrap();
+    let serialized = signed_payload(t, chain_id);
     // verifies
     peer_public_key.verify(serialized.as_ref(), signature.as_ref()).is_ok()
 }
 
+/// Verify an m-of-n signed transaction on the chain identified by `chain_id`: every signature
+/// must be by a distinct key of the set and verify, and there must be at least `threshold` of them
+pub fn verify_multisig(t: &Transaction, chain_id: &H256, threshold: u32, public_keys: &[Vec<u8>], sigs: &[(Vec<u8>, Vec<u8>)]) -> bool {
+    if threshold == 0 || threshold as usize > public_keys.len() || public_keys.len() > MAX_MULTISIG_KEYS {
+        return false;
+    }
+    if (sigs.len() as u32) < threshold {
+        return false;
+    }
+    let mut signers: Vec<&Vec<u8>> = Vec::new();
+    for (public_key, sig) in sigs {
+        if !public_keys.contains(public_key) || signers.contains(&public_key) {
+            return false;
+        }
+        if !verify(t, chain_id, public_key, sig) {
+            return false;
+        }
+        signers.push(public_key);
+    }
+    true
+}
+
+/// Verify the witness of a signed transaction on the chain identified by `chain_id`
+pub fn verify_witness(tx: &SignedTransaction, chain_id: &H256) -> bool {
+    match &tx.witness {
+        Witness::Single { sig, public_key } => verify(&tx.t, chain_id, public_key, sig),
+        Witness::Multi { threshold, public_keys, sigs } => verify_multisig(&tx.t, chain_id, *threshold, public_keys, sigs),
+        Witness::Coinbase => false,
+    }
+}
+
+/// Verify the witnesses of many transactions on the chain identified by `chain_id`. Returns the
+/// index of the first transaction whose witness does not verify.
+///
+/// Every signature is still checked on its own, as ring has no Ed25519 batch verification; only
+/// the checks run in parallel, on the current rayon thread pool, which `main` sizes at startup.
+pub fn verify_witnesses(txs: &[SignedTransaction], chain_id: &H256) -> Result<(), usize> {
+    let first_failure = if txs.len() < PARALLEL_BATCH_SIZE {
+        txs.iter().position(|tx| !verify_witness(tx, chain_id))
+    } else {
+        txs.par_iter().position_first(|tx| !verify_witness(tx, chain_id))
+    };
+    match first_failure {
+        Some(index) => Err(index),
+        None => Ok(()),
+    }
+}
+
+// bytes covered by the signature: the chain id binds the transaction to one network
+pub(crate) fn signed_payload(t: &Transaction, chain_id: &H256) -> Vec<u8> {
+    encoding::encode(&(chain_id, t))
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
     // MY CODE
 
     // makes random sender, receiver, value
+    let sender_array: [u8; 20] = rand::random();
+    let sender = Address::from(sender_array);
     let receiver_array: [u8; 20] = rand::random();
     let receiver = Address::from(receiver_array);
-    let value = rand::random();
+    let value = Amount::new(rand::random());
+    let fee = Amount::new(rand::random());
     let nonce = rand::random();
 
-    Transaction{receiver: receiver, value: value, nonce: nonce}
+    Transaction{sender, receiver, value, fee, nonce}
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -130,18 +258,81 @@ mod tests {
     fn sign_verify() {
         let t = generate_random_transaction();
         let key = key_pair::random();
-        let signature = sign(&t, &key);
-        assert!(verify(&t, key.public_key().as_ref(), signature.as_ref()));
+        let chain_id = H256::default();
+        let signature = sign(&t, &chain_id, &key);
+        assert!(verify(&t, &chain_id, key.public_key().as_ref(), signature.as_ref()));
     }
     #[test]
     fn sign_verify_two() {
         let t = generate_random_transaction();
         let key = key_pair::random();
-        let signature = sign(&t, &key);
+        let chain_id = H256::default();
+        let signature = sign(&t, &chain_id, &key);
         let key_2 = key_pair::random();
         let t_2 = generate_random_transaction();
-        assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
-        assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
+        assert!(!verify(&t_2, &chain_id, key.public_key().as_ref(), signature.as_ref()));
+        assert!(!verify(&t, &chain_id, key_2.public_key().as_ref(), signature.as_ref()));
+    }
+    #[test]
+    fn multisig_needs_threshold_distinct_keys() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|key| key.public_key().as_ref().to_vec()).collect();
+        let t = generate_random_transaction();
+        let chain_id = H256::default();
+        let pair = |i: usize| (public_keys[i].clone(), sign(&t, &chain_id, &keys[i]).as_ref().to_vec());
+
+        assert!(verify_multisig(&t, &chain_id, 2, &public_keys, &[pair(0), pair(2)]));
+        assert!(verify_multisig(&t, &chain_id, 2, &public_keys, &[pair(2), pair(1), pair(0)]));
+        // too few, the same key twice, or a key outside the set
+        assert!(!verify_multisig(&t, &chain_id, 2, &public_keys, &[pair(1)]));
+        assert!(!verify_multisig(&t, &chain_id, 2, &public_keys, &[pair(1), pair(1)]));
+        let outsider = key_pair::random();
+        let outsider_pair = (outsider.public_key().as_ref().to_vec(), sign(&t, &chain_id, &outsider).as_ref().to_vec());
+        assert!(!verify_multisig(&t, &chain_id, 2, &public_keys[..2], &[pair(0), outsider_pair]));
+        // impossible thresholds
+        assert!(!verify_multisig(&t, &chain_id, 0, &public_keys, &[]));
+        assert!(!verify_multisig(&t, &chain_id, 4, &public_keys, &[pair(0), pair(1), pair(2)]));
+
+        // the address only depends on the key set and threshold
+        let mut reversed = public_keys.clone();
+        reversed.reverse();
+        assert_eq!(Address::from_multisig(2, &public_keys), Address::from_multisig(2, &reversed));
+        assert_ne!(Address::from_multisig(2, &public_keys), Address::from_multisig(3, &public_keys));
+        let signed = SignedTransaction::new_multisig(t.clone(), 2, reversed, vec![pair(0), pair(1)]);
+        assert_eq!(signed.get_witness().address(), Some(Address::from_multisig(2, &public_keys)));
+        assert!(verify_witness(&signed, &chain_id));
+    }
+
+    #[test]
+    fn parallel_check_reports_first_bad_witness() {
+        let key = key_pair::random();
+        let chain_id = H256::default();
+        let mut txs: Vec<SignedTransaction> = (0..40).map(|_| {
+            let t = generate_random_transaction();
+            let sig = sign(&t, &chain_id, &key).as_ref().to_vec();
+            SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+        }).collect();
+        let pool = |threads: usize| rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        for threads in [1, 4] {
+            assert_eq!(pool(threads).install(|| verify_witnesses(&txs, &chain_id)), Ok(()));
+        }
+
+        for bad in [33, 17] {
+            txs[bad] = SignedTransaction::new(txs[bad].get_t(), vec![0; 64], key.public_key().as_ref().to_vec());
+        }
+        txs.push(SignedTransaction::coinbase([1; 20].into(), Amount::new(1), 1));
+        for threads in [1, 4, 64] {
+            assert_eq!(pool(threads).install(|| verify_witnesses(&txs, &chain_id)), Err(17));
+        }
+    }
+
+    #[test]
+    fn signature_is_bound_to_chain() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let signature = sign(&t, &H256::from([1; 32]), &key);
+        assert!(verify(&t, &H256::from([1; 32]), key.public_key().as_ref(), signature.as_ref()));
+        assert!(!verify(&t, &H256::from([2; 32]), key.public_key().as_ref(), signature.as_ref()));
     }
 }
 
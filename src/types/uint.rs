@@ -0,0 +1,206 @@
+use super::hash::H256;
+use std::convert::TryInto;
+
+/// A 256-bit unsigned integer, used for arithmetic on difficulty targets.
+///
+/// Converts losslessly from and to `H256`, which stores the same number in big endian.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default, Hash)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn zero() -> U256 {
+        U256([0; 4])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    /// Multiply by a u64, returning `None` on overflow
+    pub fn checked_mul_u64(&self, rhs: u64) -> Option<U256> {
+        let mut output = [0u64; 4];
+        let mut carry: u128 = 0;
+        for (i, limb) in self.0.iter().enumerate() {
+            let product = (*limb as u128) * (rhs as u128) + carry;
+            output[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            return None;
+        }
+        Some(U256(output))
+    }
+
+    /// Divide by a non-zero u64, rounding down
+    pub fn div_u64(&self, rhs: u64) -> U256 {
+        assert!(rhs != 0, "division by zero");
+        let mut output = [0u64; 4];
+        let mut remainder: u128 = 0;
+        for i in (0..4).rev() {
+            let dividend = (remainder << 64) | self.0[i] as u128;
+            output[i] = (dividend / rhs as u128) as u64;
+            remainder = dividend % rhs as u128;
+        }
+        U256(output)
+    }
+
+    /// Add, returning `None` on overflow
+    pub fn checked_add(&self, rhs: &U256) -> Option<U256> {
+        let mut output = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in output.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        if carry {
+            return None;
+        }
+        Some(U256(output))
+    }
+
+    /// Add, stopping at `U256::MAX` instead of overflowing
+    pub fn saturating_add(&self, rhs: &U256) -> U256 {
+        self.checked_add(rhs).unwrap_or(U256::MAX)
+    }
+
+    /// Divide by a non-zero U256, rounding down
+    pub fn div(&self, rhs: &U256) -> U256 {
+        assert!(!rhs.is_zero(), "division by zero");
+        // schoolbook long division, one bit at a time
+        let mut quotient = U256::zero();
+        let mut remainder = U256::zero();
+        for bit in (0..256).rev() {
+            let overflow = remainder.0[3] >> 63 == 1;
+            remainder = remainder.shl1();
+            remainder.0[0] |= (self.0[bit / 64] >> (bit % 64)) & 1;
+            if overflow || remainder >= *rhs {
+                remainder = remainder.wrapping_sub(rhs);
+                quotient.0[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        quotient
+    }
+
+    fn shl1(&self) -> U256 {
+        let mut output = [0u64; 4];
+        for (i, limb) in output.iter_mut().enumerate() {
+            *limb = self.0[i] << 1;
+            if i > 0 {
+                *limb |= self.0[i - 1] >> 63;
+            }
+        }
+        U256(output)
+    }
+
+    fn wrapping_sub(&self, rhs: &U256) -> U256 {
+        let mut output = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in output.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(rhs.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+        U256(output)
+    }
+}
+
+impl std::ops::Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> std::cmp::Ordering {
+        // compare the most significant limb first
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::convert::From<u64> for U256 {
+    fn from(input: u64) -> U256 {
+        U256([input, 0, 0, 0])
+    }
+}
+
+impl std::convert::From<&H256> for U256 {
+    fn from(input: &H256) -> U256 {
+        let bytes: &[u8] = input.as_ref();
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        (&input).into()
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in input.0.iter().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            bytes[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_and_order_match_h256() {
+        let a: H256 = hex!("0002ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff01").into();
+        let b: H256 = hex!("0003000000000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(H256::from(U256::from(a)), a);
+        assert_eq!(U256::from(a) < U256::from(b), a < b);
+    }
+
+    #[test]
+    fn mul_div() {
+        let a: U256 = H256::from(hex!("0000000000000000000000000000000100000000000000000000000000000003")).into();
+        let doubled: H256 = a.checked_mul_u64(2).unwrap().into();
+        assert_eq!(doubled, hex!("0000000000000000000000000000000200000000000000000000000000000006").into());
+        let halved: H256 = a.div_u64(2).into();
+        assert_eq!(halved, hex!("0000000000000000000000000000000080000000000000000000000000000001").into());
+        assert!(U256::MAX.checked_mul_u64(2).is_none());
+        assert_eq!(U256::from(7).div_u64(2), U256::from(3));
+    }
+
+    #[test]
+    fn add_div() {
+        let a: U256 = H256::from(hex!("00000000000000000000000000000000ffffffffffffffffffffffffffffffff")).into();
+        let sum: H256 = a.checked_add(&U256::from(1)).unwrap().into();
+        assert_eq!(sum, hex!("0000000000000000000000000000000100000000000000000000000000000000").into());
+        assert!(U256::MAX.checked_add(&U256::from(1)).is_none());
+        assert_eq!(U256::MAX.saturating_add(&U256::from(1)), U256::MAX);
+
+        let b: U256 = H256::from(hex!("0000000000000000000000000000000100000000000000000000000000000000")).into();
+        assert_eq!(U256::MAX.div(&b), a);
+        assert_eq!(U256::MAX.div(&U256::MAX), U256::from(1));
+        assert_eq!(U256::from(100).div(&U256::from(7)), U256::from(14));
+        assert_eq!(U256::from(3).div(&U256::from(7)), U256::zero());
+        assert_eq!(!U256::zero(), U256::MAX);
+    }
+}
@@ -0,0 +1,142 @@
+use ring::hmac;
+use std::fmt;
+
+/// Offset of hardened indices; Ed25519 only supports hardened derivation
+pub const HARDENED: u32 = 0x8000_0000;
+/// Path of the account keys generated by the wallet, without the final index: BIP44 purpose,
+/// coin type 1 (unregistered coins), first account, external chain
+pub const ACCOUNT_PATH: [u32; 4] = [44, 1, 0, 0];
+
+const MASTER_KEY: &[u8] = b"ed25519 seed";
+
+/// A SLIP-0010 derivation path such as `m/44'/1'/0'/0'/7'`.
+///
+/// Components are stored without the hardened offset. Every component is hardened, as SLIP-0010
+/// defines no public derivation for Ed25519, so `m/0/1` is rejected rather than silently hardened.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct DerivationPath(Vec<u32>);
+
+/// Reasons a derivation path does not parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The path does not start with `m`
+    MissingRoot,
+    /// A component is not a number below 2^31
+    InvalidIndex(String),
+    /// A component is not hardened
+    NotHardened(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::MissingRoot => write!(f, "derivation path must start with m"),
+            PathError::InvalidIndex(component) => write!(f, "invalid index {} in derivation path", component),
+            PathError::NotHardened(component) => write!(f, "index {} must be hardened for ed25519", component),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl DerivationPath {
+    /// Path of the account key with this index, below `ACCOUNT_PATH`
+    pub fn account(index: u32) -> Self {
+        let mut components = ACCOUNT_PATH.to_vec();
+        components.push(index);
+        DerivationPath(components)
+    }
+
+    /// Index of the account key at this path, if it is directly below `ACCOUNT_PATH`
+    pub fn account_index(&self) -> Option<u32> {
+        match self.0.split_last() {
+            Some((index, parent)) if parent == ACCOUNT_PATH => Some(*index),
+            _ => None,
+        }
+    }
+
+    pub fn components(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for DerivationPath {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = s.split('/');
+        if components.next() != Some("m") {
+            return Err(PathError::MissingRoot);
+        }
+        components.map(|component| {
+            let index = component.strip_suffix(|c| c == '\'' || c == 'h' || c == 'H')
+                .ok_or_else(|| PathError::NotHardened(component.to_string()))?;
+            match index.parse::<u32>() {
+                Ok(index) if index < HARDENED => Ok(index),
+                _ => Err(PathError::InvalidIndex(component.to_string())),
+            }
+        }).collect::<Result<Vec<u32>, PathError>>().map(DerivationPath)
+    }
+}
+
+/// Derive the Ed25519 private key seed at a path from a master seed, as specified by SLIP-0010
+pub fn derive(seed: &[u8], path: &DerivationPath) -> [u8; 32] {
+    let (mut key, mut chain_code) = split(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, MASTER_KEY), seed));
+    for index in path.components() {
+        let mut data = Vec::with_capacity(37);
+        data.push(0);
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        let (child_key, child_chain_code) = split(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, &chain_code), &data));
+        key = child_key;
+        chain_code = child_chain_code;
+    }
+    key
+}
+
+// the left half of an HMAC-SHA512 output is the key, the right half the chain code
+fn split(tag: hmac::Tag) -> ([u8; 32], [u8; 32]) {
+    let mut key = [0u8; 32];
+    let mut chain_code = [0u8; 32];
+    key.copy_from_slice(&tag.as_ref()[..32]);
+    chain_code.copy_from_slice(&tag.as_ref()[32..]);
+    (key, chain_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_paths() {
+        let path: DerivationPath = "m/44'/1'/0'/0'/7h".parse().unwrap();
+        assert_eq!(path, DerivationPath::account(7));
+        assert_eq!(path.to_string(), "m/44'/1'/0'/0'/7'");
+        assert_eq!(path.account_index(), Some(7));
+        assert_eq!("m/44'/1'/0'/7'".parse::<DerivationPath>().unwrap().account_index(), None);
+        assert_eq!("m".parse::<DerivationPath>().unwrap(), DerivationPath::default());
+        assert_eq!("44'/0'".parse::<DerivationPath>(), Err(PathError::MissingRoot));
+        assert_eq!("m/44'/0".parse::<DerivationPath>(), Err(PathError::NotHardened("0".to_string())));
+        assert_eq!("m/2147483648'".parse::<DerivationPath>(), Err(PathError::InvalidIndex("2147483648'".to_string())));
+    }
+
+    #[test]
+    fn matches_slip10_test_vector() {
+        // test vector 1 for ed25519 from SLIP-0010
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let derived = |path: &str| hex::encode(derive(&seed, &path.parse().unwrap()));
+        assert_eq!(derived("m"), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+        assert_eq!(derived("m/0'"), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+        assert_eq!(derived("m/0'/1'"), "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2");
+    }
+}
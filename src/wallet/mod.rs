@@ -0,0 +1,579 @@
+pub mod hd;
+
+use crate::blockchain::validation::{self, TransactionError};
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::block::State;
+use crate::types::hash::H256;
+use crate::types::transaction::{self, SignedTransaction, Transaction};
+use self::hd::{DerivationPath, HARDENED};
+
+use bip39::Mnemonic;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+/// Version of the keystore file format
+pub const KEYSTORE_VERSION: u32 = 2;
+/// PBKDF2 iterations deriving the keystore encryption key from the passphrase
+pub const KDF_ITERATIONS: u32 = 100_000;
+/// Consecutive unused account indices after which discovery stops looking for funded accounts
+pub const GAP_LIMIT: u32 = 20;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const MNEMONIC_ENTROPY_LEN: usize = 32; // 24 words
+// PKCS#8 v1 prefix of an Ed25519 private key, followed by the 32 byte seed (RFC 8410)
+const PKCS8_V1_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+
+/// Reasons a wallet operation fails.
+#[derive(Debug)]
+pub enum WalletError {
+    /// The keystore file cannot be read or written
+    Io(io::Error),
+    /// The passphrase does not decrypt the keystore, or the keystore was tampered with
+    WrongPassphrase,
+    /// The keystore file or a key in it is malformed
+    Corrupt(String),
+    /// The recovery phrase is not a valid BIP39 mnemonic
+    InvalidMnemonic(String),
+    /// The address is not one of the wallet's
+    UnknownAddress(Address),
+    /// The transfer cannot be applied to the tip state
+    InvalidTransfer(TransactionError),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletError::Io(e) => write!(f, "keystore i/o error: {}", e),
+            WalletError::WrongPassphrase => write!(f, "wrong passphrase or tampered keystore"),
+            WalletError::Corrupt(reason) => write!(f, "corrupt keystore: {}", reason),
+            WalletError::InvalidMnemonic(reason) => write!(f, "invalid mnemonic: {}", reason),
+            WalletError::UnknownAddress(address) => write!(f, "address {} is not in the wallet", address),
+            WalletError::InvalidTransfer(e) => write!(f, "invalid transfer: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+impl From<io::Error> for WalletError {
+    fn from(e: io::Error) -> Self {
+        WalletError::Io(e)
+    }
+}
+
+// on-disk form of the keystore, with the secrets encrypted
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    kdf_iterations: u32,
+    salt: String, // hex
+    nonce: String, // hex
+    ciphertext: String, // hex, with the authentication tag appended
+}
+
+// plaintext of the keystore; version 1 only held the PKCS#8 documents
+#[derive(Serialize, Deserialize)]
+struct Secrets {
+    mnemonic: String,
+    next_index: u32, // next account index under hd::ACCOUNT_PATH
+    keys: Vec<(Vec<u8>, Option<String>)>, // PKCS#8 document and derivation path, if derived
+}
+
+// where and how the keys are persisted
+struct Keystore {
+    path: PathBuf,
+    key: LessSafeKey, // derived from the passphrase
+    salt: [u8; SALT_LEN],
+    iterations: u32,
+}
+
+struct Key {
+    document: Vec<u8>, // PKCS#8
+    path: Option<DerivationPath>, // none for imported keys
+    key_pair: Ed25519KeyPair,
+}
+
+/// An account of the wallet as seen in a state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletAccount {
+    pub address: Address,
+    pub path: Option<String>, // derivation path, none for imported keys
+    pub balance: Amount,
+    pub next_nonce: u64, // nonce of the next transaction sent from this account
+}
+
+/// Keys owned by this node, kept encrypted in a keystore file across restarts.
+///
+/// Account keys are derived with SLIP-0010 from the seed of a BIP39 mnemonic, at consecutive
+/// indices below `hd::ACCOUNT_PATH`, so the mnemonic alone restores them. Keys can also be imported
+/// from a raw seed; those are only as durable as the keystore.
+///
+/// The keystore is a JSON file holding the mnemonic and the PKCS#8 documents of all keys, encrypted
+/// with ChaCha20-Poly1305 under a key derived from a passphrase with PBKDF2-HMAC-SHA256. It is
+/// rewritten whenever a key is added. A wallet without a keystore keeps its keys in memory only.
+pub struct Wallet {
+    keystore: Option<Keystore>,
+    mnemonic: Mnemonic,
+    seed: [u8; 64], // BIP39 seed of the mnemonic, with an empty passphrase
+    next_index: u32,
+    keys: Vec<Key>,
+}
+
+impl Wallet {
+    /// Create a wallet with a fresh mnemonic that is not persisted
+    pub fn in_memory() -> Self {
+        Self::with_mnemonic(None, generate_mnemonic().expect("system randomness is available"))
+    }
+
+    /// Create a wallet from a mnemonic that is not persisted
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, WalletError> {
+        Ok(Self::with_mnemonic(None, parse_mnemonic(phrase)?))
+    }
+
+    /// Create an empty keystore file with a fresh mnemonic; fails if the file exists
+    pub fn create(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        Self::create_with(path, passphrase, generate_mnemonic()?, KDF_ITERATIONS)
+    }
+
+    /// Create a keystore file for the keys of a mnemonic; fails if the file exists.
+    /// Derived accounts are brought back with `discover`.
+    pub fn restore(path: &Path, passphrase: &str, phrase: &str) -> Result<Self, WalletError> {
+        Self::create_with(path, passphrase, parse_mnemonic(phrase)?, KDF_ITERATIONS)
+    }
+
+    fn create_with(path: &Path, passphrase: &str, mnemonic: Mnemonic, iterations: u32) -> Result<Self, WalletError> {
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())).into());
+        }
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| WalletError::Corrupt("no randomness".to_string()))?;
+        let key = derive_key(passphrase, &salt, iterations)?;
+        let keystore = Keystore { path: path.to_path_buf(), key, salt, iterations };
+        let wallet = Self::with_mnemonic(Some(keystore), mnemonic);
+        wallet.save()?;
+        Ok(wallet)
+    }
+
+    fn with_mnemonic(keystore: Option<Keystore>, mnemonic: Mnemonic) -> Self {
+        let seed = mnemonic.to_seed("");
+        Wallet { keystore, mnemonic, seed, next_index: 0, keys: Vec::new() }
+    }
+
+    /// Open and decrypt an existing keystore file
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        let file: KeystoreFile = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| WalletError::Corrupt(e.to_string()))?;
+        if file.version == 0 || file.version > KEYSTORE_VERSION {
+            return Err(WalletError::Corrupt(format!("unsupported version {}", file.version)));
+        }
+        let salt: [u8; SALT_LEN] = decode_hex(&file.salt)?;
+        let nonce: [u8; aead::NONCE_LEN] = decode_hex(&file.nonce)?;
+        let mut ciphertext = hex::decode(&file.ciphertext).map_err(|e| WalletError::Corrupt(e.to_string()))?;
+
+        let key = derive_key(passphrase, &salt, file.kdf_iterations)?;
+        let plaintext = key.open_in_place(Nonce::assume_unique_for_key(nonce), aad(file.version), &mut ciphertext)
+            .map_err(|_| WalletError::WrongPassphrase)?;
+        let keystore = Keystore { path: path.to_path_buf(), key, salt, iterations: file.kdf_iterations };
+
+        if file.version == 1 {
+            // keys of a version 1 keystore were random; keep them as imported keys and upgrade
+            // the keystore with a fresh mnemonic for new accounts
+            let documents: Vec<Vec<u8>> = bincode::deserialize(plaintext)
+                .map_err(|e| WalletError::Corrupt(e.to_string()))?;
+            let mut wallet = Self::with_mnemonic(Some(keystore), generate_mnemonic()?);
+            for document in documents {
+                wallet.keys.push(parse_key(document, None)?);
+            }
+            wallet.save()?;
+            return Ok(wallet);
+        }
+
+        let secrets: Secrets = bincode::deserialize(plaintext)
+            .map_err(|e| WalletError::Corrupt(e.to_string()))?;
+        let mnemonic = parse_mnemonic(&secrets.mnemonic)
+            .map_err(|e| WalletError::Corrupt(e.to_string()))?;
+        let mut wallet = Self::with_mnemonic(Some(keystore), mnemonic);
+        wallet.next_index = secrets.next_index;
+        for (document, path) in secrets.keys {
+            let path = match path {
+                Some(path) => Some(path.parse::<DerivationPath>().map_err(|e| WalletError::Corrupt(e.to_string()))?),
+                None => None,
+            };
+            wallet.keys.push(parse_key(document, path)?);
+        }
+        Ok(wallet)
+    }
+
+    /// Open the keystore file, creating an empty one if there is none
+    pub fn open_or_create(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        if path.exists() {
+            Self::open(path, passphrase)
+        } else {
+            Self::create(path, passphrase)
+        }
+    }
+
+    /// The recovery phrase all derived keys come from
+    pub fn mnemonic(&self) -> String {
+        self.mnemonic.to_string()
+    }
+
+    /// Derive the key at the next account index, persist it, and return its address
+    pub fn new_address(&mut self) -> Result<Address, WalletError> {
+        if self.next_index >= HARDENED {
+            return Err(WalletError::Corrupt("account indices exhausted".to_string()));
+        }
+        self.derive_address(&DerivationPath::account(self.next_index))
+    }
+
+    /// Derive the key at a path, persist it unless the wallet already has it, and return its
+    /// address. An account path at or past the next account index moves that index past it, so
+    /// that `new_address` never hands out the same address again.
+    pub fn derive_address(&mut self, path: &DerivationPath) -> Result<Address, WalletError> {
+        let previous_index = self.next_index;
+        if let Some(index) = path.account_index() {
+            self.next_index = std::cmp::max(self.next_index, index.saturating_add(1));
+        }
+        let document = pkcs8_from_seed(&hd::derive(&self.seed, path));
+        self.add(document, Some(path.clone())).inspect_err(|_| self.next_index = previous_index)
+    }
+
+    /// Derive the account keys that were used in a state, scanning indices until `GAP_LIMIT`
+    /// consecutive ones are unused, and return how many accounts were added
+    pub fn discover(&mut self, state: &State) -> Result<u32, WalletError> {
+        let first = self.next_index;
+        let mut index = first;
+        let mut unused = 0;
+        while unused < GAP_LIMIT && index < HARDENED {
+            let key = parse_key(pkcs8_from_seed(&hd::derive(&self.seed, &DerivationPath::account(index))), None)?;
+            if state.get(address_of(&key.key_pair)).is_some() {
+                // earlier unused indices are taken too, so that new_address stays past them
+                while self.next_index <= index {
+                    self.new_address()?;
+                }
+                unused = 0;
+            } else {
+                unused += 1;
+            }
+            index += 1;
+        }
+        Ok(self.next_index - first)
+    }
+
+    /// Add the key with this seed, unless the wallet already has it, and return its address
+    pub fn import_seed(&mut self, seed: &[u8; 32]) -> Result<Address, WalletError> {
+        self.add(pkcs8_from_seed(seed), None)
+    }
+
+    fn add(&mut self, document: Vec<u8>, path: Option<DerivationPath>) -> Result<Address, WalletError> {
+        let key = parse_key(document, path)?;
+        let address = address_of(&key.key_pair);
+        if self.contains(&address) {
+            // still persist a moved account index
+            self.save()?;
+            return Ok(address);
+        }
+        self.keys.push(key);
+        if let Err(e) = self.save() {
+            self.keys.pop();
+            return Err(e);
+        }
+        Ok(address)
+    }
+
+    /// Addresses of all keys, in the order they were added
+    pub fn addresses(&self) -> Vec<Address> {
+        self.keys.iter().map(|key| address_of(&key.key_pair)).collect()
+    }
+
+    /// Derivation path of an address, if the wallet derived its key
+    pub fn path_of(&self, address: &Address) -> Option<&DerivationPath> {
+        self.key(address).and_then(|key| key.path.as_ref())
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.key(address).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Balance and next nonce of every address in the state; addresses not in the state yet have
+    /// nothing
+    pub fn accounts(&self, state: &State) -> Vec<WalletAccount> {
+        self.keys.iter().map(|key| {
+            let address = address_of(&key.key_pair);
+            let (nonce, balance) = state.get(address).unwrap_or((0, Amount::ZERO));
+            WalletAccount {
+                address,
+                path: key.path.as_ref().map(|path| path.to_string()),
+                balance,
+                next_nonce: nonce.saturating_add(1),
+            }
+        }).collect()
+    }
+
+    /// Build and sign a transfer from one of the wallet's addresses, checked against `state`.
+    ///
+    /// Pass the tip state with the sender's pending transactions applied, from
+    /// `Mempool::pending_state`, so that back-to-back transfers take consecutive nonces.
+    pub fn transfer(&self, state: &State, chain_id: &H256, from: Address, to: Address, value: Amount, fee: Amount) -> Result<SignedTransaction, WalletError> {
+        let key_pair = &self.key(&from).ok_or(WalletError::UnknownAddress(from))?.key_pair;
+        let (nonce, _) = state.get(from)
+            .ok_or(WalletError::InvalidTransfer(TransactionError::UnknownSender(from)))?;
+        let nonce = nonce.checked_add(1).ok_or(WalletError::InvalidTransfer(TransactionError::Overflow))?;
+
+        let t = Transaction::new(from, to, value, fee, nonce);
+        let sig = transaction::sign(&t, chain_id, key_pair).as_ref().to_vec();
+        let tx = SignedTransaction::new(t, sig, key_pair.public_key().as_ref().to_vec());
+        validation::check_transaction(state, &tx).map_err(WalletError::InvalidTransfer)?;
+        Ok(tx)
+    }
+
+    fn key(&self, address: &Address) -> Option<&Key> {
+        self.keys.iter().find(|key| address_of(&key.key_pair) == *address)
+    }
+
+    // encrypt all secrets with a fresh nonce and replace the keystore file
+    fn save(&self) -> Result<(), WalletError> {
+        let keystore = match &self.keystore {
+            Some(keystore) => keystore,
+            None => return Ok(()),
+        };
+        let secrets = Secrets {
+            mnemonic: self.mnemonic(),
+            next_index: self.next_index,
+            keys: self.keys.iter()
+                .map(|key| (key.document.clone(), key.path.as_ref().map(|path| path.to_string())))
+                .collect(),
+        };
+        let plaintext = bincode::serialize(&secrets).map_err(|e| WalletError::Corrupt(e.to_string()))?;
+        let file = seal(keystore, KEYSTORE_VERSION, plaintext)?;
+        let encoded = serde_json::to_vec_pretty(&file).map_err(|e| WalletError::Corrupt(e.to_string()))?;
+
+        // write a temporary file and rename it over the keystore, so a crash never leaves half of it
+        let temporary = keystore.path.with_extension("tmp");
+        fs::write(&temporary, encoded)?;
+        fs::rename(&temporary, &keystore.path)?;
+        Ok(())
+    }
+}
+
+// encrypt the plaintext of a keystore with a fresh nonce
+fn seal(keystore: &Keystore, version: u32, mut plaintext: Vec<u8>) -> Result<KeystoreFile, WalletError> {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| WalletError::Corrupt("no randomness".to_string()))?;
+    keystore.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), aad(version), &mut plaintext)
+        .map_err(|_| WalletError::Corrupt("cannot encrypt keystore".to_string()))?;
+    Ok(KeystoreFile {
+        version,
+        kdf_iterations: keystore.iterations,
+        salt: hex::encode(keystore.salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(&plaintext),
+    })
+}
+
+// the associated data binds the ciphertext to the file format version
+fn aad(version: u32) -> Aad<[u8; 4]> {
+    Aad::from(version.to_be_bytes())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, WalletError> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| WalletError::Corrupt("zero kdf iterations".to_string()))?;
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key)
+        .map_err(|_| WalletError::Corrupt("bad key length".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn generate_mnemonic() -> Result<Mnemonic, WalletError> {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+    SystemRandom::new().fill(&mut entropy).map_err(|_| WalletError::Corrupt("no randomness".to_string()))?;
+    Mnemonic::from_entropy(&entropy).map_err(|e| WalletError::InvalidMnemonic(e.to_string()))
+}
+
+fn parse_mnemonic(phrase: &str) -> Result<Mnemonic, WalletError> {
+    Mnemonic::parse(phrase).map_err(|e| WalletError::InvalidMnemonic(e.to_string()))
+}
+
+fn pkcs8_from_seed(seed: &[u8; 32]) -> Vec<u8> {
+    let mut document = PKCS8_V1_PREFIX.to_vec();
+    document.extend_from_slice(seed);
+    document
+}
+
+fn parse_key(document: Vec<u8>, path: Option<DerivationPath>) -> Result<Key, WalletError> {
+    let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&document)
+        .map_err(|e| WalletError::Corrupt(e.to_string()))?;
+    Ok(Key { document, path, key_pair })
+}
+
+fn address_of(key_pair: &Ed25519KeyPair) -> Address {
+    Address::from_public_key_bytes(key_pair.public_key().as_ref())
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N], WalletError> {
+    let mut buffer = [0u8; N];
+    hex::decode_to_slice(s, &mut buffer).map_err(|e| WalletError::Corrupt(e.to_string()))?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Mempool;
+
+    // keystores in tests use few iterations, so they open quickly in debug builds
+    const TEST_ITERATIONS: u32 = 10;
+
+    fn keystore_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wallet-test-{}-{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn keys_survive_reopening() {
+        let path = keystore_path("reopen");
+        let mut wallet = Wallet::create_with(&path, "correct horse", generate_mnemonic().unwrap(), TEST_ITERATIONS).unwrap();
+        let first = wallet.new_address().unwrap();
+        let second = wallet.import_seed(&[0; 32]).unwrap();
+        assert_eq!(wallet.import_seed(&[0; 32]).unwrap(), second);
+        assert_eq!(wallet.len(), 2);
+        assert!(Wallet::create(&path, "correct horse").is_err());
+
+        let mut reopened = Wallet::open(&path, "correct horse").unwrap();
+        assert_eq!(reopened.addresses(), vec![first, second]);
+        assert_eq!(reopened.mnemonic(), wallet.mnemonic());
+        // the account index is persisted too
+        assert_ne!(reopened.new_address().unwrap(), first);
+        // the seed 0 key is the genesis key, whose address is fixed
+        let genesis = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        assert_eq!(second, Address::from_public_key_bytes(genesis.public_key().as_ref()));
+
+        match Wallet::open(&path, "wrong horse") {
+            Err(WalletError::WrongPassphrase) => {}
+            other => panic!("unexpected result {:?}", other.map(|wallet| wallet.addresses())),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn upgrades_version_1_keystores() {
+        let path = keystore_path("upgrade");
+        let salt = [7u8; SALT_LEN];
+        let keystore = Keystore { path: path.clone(), key: derive_key("pass", &salt, TEST_ITERATIONS).unwrap(), salt, iterations: TEST_ITERATIONS };
+        let documents = vec![pkcs8_from_seed(&[3; 32])];
+        let file = seal(&keystore, 1, bincode::serialize(&documents).unwrap()).unwrap();
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+
+        let wallet = Wallet::open(&path, "pass").unwrap();
+        let imported = Address::from_public_key_bytes(Ed25519KeyPair::from_seed_unchecked(&[3; 32]).unwrap().public_key().as_ref());
+        assert_eq!(wallet.addresses(), vec![imported]);
+        let reopened = Wallet::open(&path, "pass").unwrap();
+        assert_eq!(reopened.mnemonic(), wallet.mnemonic());
+        assert_eq!(reopened.accounts(&State::new())[0].path, None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mnemonic_restores_used_accounts() {
+        let mut wallet = Wallet::in_memory();
+        let addresses: Vec<Address> = (0..50).map(|_| wallet.new_address().unwrap()).collect();
+        let accounts = wallet.accounts(&State::new());
+        assert_eq!(accounts[49].path.as_deref(), Some("m/44'/1'/0'/0'/49'"));
+
+        // accounts 3, 23 and 44 received coins; 44 is past the gap limit after 23
+        let mut state = State::new();
+        for index in [3, 23, 44] {
+            state.insert(addresses[index], 0, Amount::new(5));
+        }
+        let mut restored = Wallet::from_mnemonic(&wallet.mnemonic()).unwrap();
+        assert_eq!(restored.discover(&state).unwrap(), 24);
+        assert_eq!(restored.addresses(), addresses[..24].to_vec());
+        assert_eq!(restored.discover(&state).unwrap(), 0);
+        assert_eq!(restored.new_address().unwrap(), addresses[24]);
+
+        assert!(matches!(Wallet::from_mnemonic("not a phrase"), Err(WalletError::InvalidMnemonic(_))));
+    }
+
+    #[test]
+    fn derived_accounts_are_not_handed_out_again() {
+        let mut wallet = Wallet::in_memory();
+        let first = wallet.new_address().unwrap();
+        let third = wallet.derive_address(&DerivationPath::account(2)).unwrap();
+        // paths outside the accounts and earlier accounts leave the index alone
+        wallet.derive_address(&"m/44'/1'/0'/1'/9'".parse().unwrap()).unwrap();
+        assert_eq!(wallet.derive_address(&DerivationPath::account(0)).unwrap(), first);
+
+        let next = wallet.new_address().unwrap();
+        assert!(next != first && next != third);
+        assert_eq!(wallet.path_of(&next), Some(&DerivationPath::account(3)));
+    }
+
+    #[test]
+    fn builds_transfers_against_the_state() {
+        let mut wallet = Wallet::in_memory();
+        let sender = wallet.import_seed(&[0; 32]).unwrap();
+        let stranger: Address = [9; 20].into();
+        let chain_id = H256::from([1; 32]);
+        let mut state = State::new();
+        state.insert(sender, 4, Amount::new(100));
+
+        let accounts = wallet.accounts(&state);
+        assert_eq!(accounts, vec![WalletAccount { address: sender, path: None, balance: Amount::new(100), next_nonce: 5 }]);
+
+        let tx = wallet.transfer(&state, &chain_id, sender, stranger, Amount::new(60), Amount::new(5)).unwrap();
+        assert_eq!(tx.get_t().get_nonce(), 5);
+        assert_eq!(validation::validate_transaction(&state, &tx, &chain_id), Ok(()));
+
+        match wallet.transfer(&state, &chain_id, sender, stranger, Amount::new(100), Amount::new(1)) {
+            Err(WalletError::InvalidTransfer(TransactionError::InsufficientBalance { .. })) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match wallet.transfer(&state, &chain_id, stranger, sender, Amount::new(1), Amount::ZERO) {
+            Err(WalletError::UnknownAddress(address)) => assert_eq!(address, stranger),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn back_to_back_transfers_take_consecutive_nonces() {
+        let mut wallet = Wallet::in_memory();
+        let sender = wallet.import_seed(&[0; 32]).unwrap();
+        let stranger: Address = [9; 20].into();
+        let chain_id = H256::from([1; 32]);
+        let mut state = State::new();
+        state.insert(sender, 4, Amount::new(100));
+        let mut mempool = Mempool::new();
+
+        let first = wallet.transfer(&mempool.pending_state(&state, sender), &chain_id, sender, stranger, Amount::new(30), Amount::new(5)).unwrap();
+        assert!(mempool.insert(first.txid(), &first));
+        let second = wallet.transfer(&mempool.pending_state(&state, sender), &chain_id, sender, stranger, Amount::new(30), Amount::new(5)).unwrap();
+        assert_eq!((first.get_t().get_nonce(), second.get_t().get_nonce()), (5, 6));
+        assert!(mempool.insert(second.txid(), &second));
+
+        // the pending transfers already spend 70 of the 100
+        match wallet.transfer(&mempool.pending_state(&state, sender), &chain_id, sender, stranger, Amount::new(30), Amount::new(5)) {
+            Err(WalletError::InvalidTransfer(TransactionError::InsufficientBalance { .. })) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}